@@ -0,0 +1,296 @@
+use crate::history::{self, Filter, Shell, TimeRange};
+use crate::keymap::{self, Action, Keymap};
+use crate::matcher::{MatchMode, SortOrder};
+use crate::store::Backend;
+use crate::theme::Theme;
+use color_eyre::Result;
+use color_eyre::eyre::{WrapErr, bail, eyre};
+use ratatui::style::Color;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The commented default config printed by `iff config --print-default`.
+pub const DEFAULT_CONFIG: &str = include_str!("default_config.toml");
+
+/// Settings from `config.toml`. Command-line flags take precedence over all
+/// of them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub history: HistoryConfig,
+    pub search: SearchConfig,
+    pub filter: FilterConfig,
+    pub exec: ExecConfig,
+    pub ui: UiConfig,
+    pub theme: ThemeConfig,
+    pub keymap: KeymapConfig,
+    /// Where this config was read from, for error messages
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Read these instead of searching the default locations
+    pub files: Vec<PathBuf>,
+    pub shell: Option<Shell>,
+    /// Where `iff record` keeps iff's own history
+    pub backend: Backend,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchConfig {
+    pub mode: MatchMode,
+    pub sort: SortOrder,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    /// Same syntax as `--since`
+    pub since: Option<String>,
+    pub until: Option<String>,
+    /// Regexes of commands to hide
+    pub exclude: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExecConfig {
+    pub shell: Option<PathBuf>,
+    pub interactive: bool,
+    pub direct: bool,
+}
+
+/// The fewest rows the inline UI fits in: search box, a one-row list and the
+/// status bar.
+pub const MIN_HEIGHT: u16 = 7;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiConfig {
+    /// Draw inline with this many rows instead of fullscreen
+    pub height: Option<u16>,
+    /// Start with the preview pane open
+    pub preview: bool,
+}
+
+/// Color names, 0-255 indexes or `#rrggbb`, parsed by [`Config::theme`].
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    pub search: Option<String>,
+    pub list: Option<String>,
+    pub edit: Option<String>,
+    pub highlight: Option<String>,
+    pub selection: Option<String>,
+}
+
+/// Overrides on top of the default keymap, e.g.
+///
+/// ```toml
+/// [keymap.insert]
+/// ctrl-j = "select-next"
+///
+/// [keymap.normal]
+/// "g g" = "select-first"
+/// q = "ignore"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeymapConfig {
+    pub insert: HashMap<String, Action>,
+    pub normal: HashMap<String, Action>,
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/iff/config.toml`, falling back to `~/.config`.
+    pub fn default_path() -> PathBuf {
+        Dirs::from_env().config.join("iff").join("config.toml")
+    }
+
+    /// Loads and validates the config at `path`, or at the default location.
+    /// A missing file at the default location just means the defaults.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let explicit = path.is_some();
+        let path = path.map_or_else(Self::default_path, Path::to_path_buf);
+        if !explicit && !path.exists() {
+            return Ok(Self {
+                path,
+                ..Self::default()
+            });
+        }
+        let content = fs::read_to_string(&path)
+            .wrap_err_with(|| format!("failed to read {}", path.display()))?;
+        let mut config: Config = toml::from_str(&content)
+            .wrap_err_with(|| format!("invalid config in {}", path.display()))?;
+        config.path = path;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks everything serde can't, so mistakes show up on startup
+    /// whichever flags are given.
+    fn validate(&self) -> Result<()> {
+        self.filter()?;
+        self.theme()?;
+        self.keymap()?;
+        if let Some(height) = self.ui.height
+            && height < MIN_HEIGHT
+        {
+            bail!(
+                "`height` must be at least {MIN_HEIGHT} rows, not {height} {}",
+                self.location("ui")
+            );
+        }
+        if self.exec.direct && (self.exec.shell.is_some() || self.exec.interactive) {
+            bail!(
+                "`direct` runs commands without a shell, so it can't be combined with \
+                 `shell` or `interactive` {}",
+                self.location("exec")
+            );
+        }
+        Ok(())
+    }
+
+    fn location(&self, section: &str) -> String {
+        format!("in [{section}] of {}", self.path.display())
+    }
+
+    /// The configured history files, with a leading `~` expanded.
+    pub fn history_files(&self) -> Vec<PathBuf> {
+        self.history
+            .files
+            .iter()
+            .map(|path| expand_home(path))
+            .collect()
+    }
+
+    pub fn filter(&self) -> Result<Filter> {
+        let parse = |field: &str, value: &Option<String>| {
+            value
+                .as_deref()
+                .map(history::parse_time)
+                .transpose()
+                .map_err(|err| eyre!("invalid `{field}` {}: {err}", self.location("filter")))
+        };
+        let time_range = TimeRange {
+            since: parse("since", &self.filter.since)?,
+            until: parse("until", &self.filter.until)?,
+        };
+        if let (Some(since), Some(until)) = (time_range.since, time_range.until)
+            && since > until
+        {
+            bail!(
+                "`since` is later than `until` {}, so nothing would be shown",
+                self.location("filter")
+            );
+        }
+
+        let exclude = self
+            .filter
+            .exclude
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern).wrap_err_with(|| {
+                    format!(
+                        "invalid `exclude` pattern {pattern:?} {}",
+                        self.location("filter")
+                    )
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Filter {
+            time_range,
+            exclude,
+            ..Filter::default()
+        })
+    }
+
+    pub fn theme(&self) -> Result<Theme> {
+        let mut theme = Theme::default();
+        for (field, value, color) in [
+            ("search", &self.theme.search, &mut theme.search),
+            ("list", &self.theme.list, &mut theme.list),
+            ("edit", &self.theme.edit, &mut theme.edit),
+            ("highlight", &self.theme.highlight, &mut theme.highlight),
+            ("selection", &self.theme.selection, &mut theme.selection),
+        ] {
+            let Some(value) = value else {
+                continue;
+            };
+            *color = value.parse::<Color>().map_err(|_| {
+                eyre!(
+                    "invalid color {value:?} for `{field}` {}; expected a name like \
+                     \"cyan\" or \"light-red\", a number from 0 to 255, or \"#rrggbb\"",
+                    self.location("theme")
+                )
+            })?;
+        }
+        Ok(theme)
+    }
+
+    pub fn keymap(&self) -> Result<Keymap> {
+        let mut keymap = Keymap::default();
+        for (bindings, overrides, mode) in [
+            (&mut keymap.insert, &self.keymap.insert, "insert"),
+            (&mut keymap.normal, &self.keymap.normal, "normal"),
+        ] {
+            for (keys, action) in overrides {
+                let keys = keymap::parse_sequence(keys)
+                    .wrap_err_with(|| self.location(&format!("keymap.{mode}")))?;
+                bindings.bind(keys, *action);
+            }
+        }
+        Ok(keymap)
+    }
+}
+
+/// The home directory and the XDG base directories under it. Each XDG
+/// variable is only used if it's an absolute path, as the spec says.
+pub struct Dirs {
+    pub home: PathBuf,
+    /// `$XDG_CONFIG_HOME`, falling back to `~/.config`
+    pub config: PathBuf,
+    /// `$XDG_DATA_HOME`, falling back to `~/.local/share`
+    pub data: PathBuf,
+    /// `$XDG_STATE_HOME`, falling back to `~/.local/state`
+    pub state: PathBuf,
+}
+
+impl Dirs {
+    pub fn from_env() -> Self {
+        Self::from_vars(|var| env::var_os(var))
+    }
+
+    /// With `var` looking up the environment variables.
+    pub fn from_vars(var: impl Fn(&str) -> Option<OsString>) -> Self {
+        let home = PathBuf::from(var("HOME").expect("HOME isn't set"));
+        let xdg_dir = |name: &str, fallback: &str| {
+            var(name)
+                .map(PathBuf::from)
+                .filter(|path| path.is_absolute())
+                .unwrap_or_else(|| home.join(fallback))
+        };
+        Dirs {
+            config: xdg_dir("XDG_CONFIG_HOME", ".config"),
+            data: xdg_dir("XDG_DATA_HOME", ".local/share"),
+            state: xdg_dir("XDG_STATE_HOME", ".local/state"),
+            home,
+        }
+    }
+}
+
+fn expand_home(path: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(rest) => Dirs::from_env().home.join(rest),
+        Err(_) => path.to_path_buf(),
+    }
+}
@@ -0,0 +1,460 @@
+//! The SQLite backend for iff's own history.
+
+use crate::history::{self, Filter, HistoryEntry, Shell};
+use crate::store::Record;
+use clap::ValueEnum;
+use color_eyre::Result;
+use color_eyre::eyre::{WrapErr, bail};
+use rusqlite::types::Value;
+use rusqlite::{
+    Connection, OptionalExtension, Transaction, TransactionBehavior, params, params_from_iter,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Schema changes, applied in order. `PRAGMA user_version` records how many
+/// have run, so only ever append to this.
+const MIGRATIONS: &[&str] = &[
+    // 1: entries, the shell sessions they ran in, and free-form tags
+    "
+    CREATE TABLE sessions (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        shell TEXT NOT NULL,
+        hostname TEXT,
+        started_at INTEGER NOT NULL
+    );
+
+    CREATE TABLE entries (
+        id INTEGER PRIMARY KEY,
+        command TEXT NOT NULL,
+        shell TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        duration INTEGER,
+        cwd TEXT,
+        exit_status INTEGER,
+        hostname TEXT,
+        session_id INTEGER REFERENCES sessions (id) ON DELETE SET NULL
+    );
+    CREATE INDEX entries_timestamp ON entries (timestamp);
+    CREATE INDEX entries_cwd ON entries (cwd, timestamp);
+    CREATE INDEX entries_exit_status ON entries (exit_status, timestamp);
+    CREATE INDEX entries_command ON entries (command, timestamp);
+
+    CREATE TABLE tags (
+        entry_id INTEGER NOT NULL REFERENCES entries (id) ON DELETE CASCADE,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (entry_id, tag)
+    );
+    CREATE INDEX tags_tag ON tags (tag);
+    ",
+    // 2: files whose records have been copied in, so it happens only once
+    "
+    CREATE TABLE imports (
+        path TEXT PRIMARY KEY,
+        imported_at INTEGER NOT NULL
+    );
+    ",
+];
+
+/// Separates tags in the `group_concat` of a query; can't appear in a tag.
+const TAG_SEPARATOR: char = '\x1f';
+
+/// Parses a `--tag`, refusing the one character a tag can't contain.
+pub fn parse_tag(s: &str) -> Result<String, String> {
+    if s.contains(TAG_SEPARATOR) {
+        return Err("tags can't contain the unit separator character (0x1f)".to_string());
+    }
+    Ok(s.to_string())
+}
+
+pub struct Database {
+    conn: Connection,
+}
+
+impl Database {
+    /// Opens (creating if needed) the database at `path` and brings its
+    /// schema up to date.
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("failed to create {}", dir.display()))?;
+        }
+        let conn = Connection::open(path)
+            .wrap_err_with(|| format!("failed to open {}", path.display()))?;
+        // Every shell writes after each command, so wait out the others
+        // rather than failing
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+
+        let mut db = Database { conn };
+        db.migrate()
+            .wrap_err_with(|| format!("failed to migrate {}", path.display()))?;
+        Ok(db)
+    }
+
+    fn migrate(&mut self) -> Result<()> {
+        let version: usize = self
+            .conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            bail!(
+                "the database is at schema version {version}, but this iff only knows up to {}; \
+                 it was written by a newer iff",
+                MIGRATIONS.len()
+            );
+        }
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = self.conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Adds `records` in a single transaction.
+    pub fn insert(&mut self, records: &[Record]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        for record in records {
+            insert_record(&tx, record)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Whether the records in `path` have been added by
+    /// [`Database::import_once`].
+    pub fn has_imported(&self, path: &Path) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT 1 FROM imports WHERE path = ?1",
+                [path.to_string_lossy()],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
+    /// Adds `records`, read from `path`, unless they already have been; a
+    /// shell starting at the same moment may have got there first.
+    pub fn import_once(&mut self, path: &Path, records: &[Record]) -> Result<()> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let added = tx.execute(
+            "INSERT OR IGNORE INTO imports (path, imported_at) VALUES (?1, ?2)",
+            params![path.to_string_lossy(), history::now()],
+        )?;
+        if added > 0 {
+            for record in records {
+                insert_record(&tx, record)?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Entries passing `filter`'s time range, shell, directory and exit
+    /// status, oldest first. Those are answered from the indexes; the
+    /// exclude patterns are left to the caller.
+    pub fn query(&self, filter: &Filter) -> Result<Vec<HistoryEntry>> {
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+        let mut condition = |sql: &str, value: Value| {
+            values.push(value);
+            conditions.push(format!("{sql} ?{}", values.len()));
+        };
+        if let Some(since) = filter.time_range.since {
+            condition("e.timestamp >=", Value::Integer(to_i64(since)));
+        }
+        if let Some(until) = filter.time_range.until {
+            condition("e.timestamp <=", Value::Integer(to_i64(until)));
+        }
+        if let Some(shell) = filter.shell {
+            condition("e.shell =", Value::Text(shell.name().to_string()));
+        }
+        if let Some(cwd) = &filter.cwd {
+            condition("e.cwd =", Value::Text(cwd.to_string_lossy().into_owned()));
+        }
+        if let Some(status) = filter.exit_status {
+            condition("e.exit_status =", Value::Integer(status.into()));
+        }
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+
+        let sql = format!(
+            "SELECT e.command, e.shell, e.timestamp, e.duration, e.cwd, e.exit_status,
+                    e.hostname, s.name,
+                    (SELECT group_concat(tag, char(31)) FROM tags WHERE entry_id = e.id)
+             FROM entries e LEFT JOIN sessions s ON s.id = e.session_id
+             {where_clause}
+             ORDER BY e.timestamp, e.id"
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(params_from_iter(values), |row| {
+            let shell: String = row.get(1)?;
+            let tags: Option<String> = row.get(8)?;
+            Ok(HistoryEntry {
+                timestamp: row.get(2)?,
+                duration: row.get(3)?,
+                cwd: row.get::<_, Option<String>>(4)?.map(PathBuf::from),
+                exit_status: row.get(5)?,
+                hostname: row.get(6)?,
+                session: row.get(7)?,
+                tags: tags
+                    .map(|tags| tags.split(TAG_SEPARATOR).map(str::to_string).collect())
+                    .unwrap_or_default(),
+                ..HistoryEntry::new(
+                    row.get(0)?,
+                    Shell::from_str(&shell, true).unwrap_or(Shell::Bash),
+                )
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+fn insert_record(tx: &Transaction, record: &Record) -> Result<()> {
+    let session_id = match &record.session {
+        Some(name) => {
+            tx.execute(
+                "INSERT INTO sessions (name, shell, hostname, started_at)
+                 VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (name) DO NOTHING",
+                params![name, record.shell.name(), record.hostname, record.timestamp],
+            )?;
+            tx.query_row("SELECT id FROM sessions WHERE name = ?1", [name], |row| {
+                row.get::<_, i64>(0)
+            })
+            .optional()?
+        }
+        None => None,
+    };
+    tx.execute(
+        "INSERT INTO entries
+             (command, shell, timestamp, duration, cwd, exit_status, hostname, session_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            record.command,
+            record.shell.name(),
+            record.timestamp,
+            record.duration,
+            record.cwd.as_deref().map(Path::to_string_lossy),
+            record.exit_status,
+            record.hostname,
+            session_id,
+        ],
+    )?;
+    let entry_id = tx.last_insert_rowid();
+    for tag in &record.tags {
+        tx.execute(
+            "INSERT OR IGNORE INTO tags (entry_id, tag) VALUES (?1, ?2)",
+            params![entry_id, tag],
+        )?;
+    }
+    Ok(())
+}
+
+fn to_i64(secs: u64) -> i64 {
+    i64::try_from(secs).unwrap_or(i64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::TimeRange;
+    use std::env;
+
+    /// A fresh database file path in a directory of its own.
+    fn temp_db(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("iff-test-{}-db-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("history.db")
+    }
+
+    fn record(command: &str, timestamp: u64) -> Record {
+        Record {
+            command: command.to_string(),
+            shell: Shell::Bash,
+            timestamp,
+            duration: None,
+            cwd: None,
+            exit_status: None,
+            hostname: None,
+            session: None,
+            tags: Vec::new(),
+        }
+    }
+
+    fn commands(entries: &[HistoryEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.command.as_str()).collect()
+    }
+
+    fn user_version(db: &Database) -> usize {
+        db.conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn migrates_once_and_refuses_newer_schemas() {
+        let path = temp_db("migrate");
+        let mut db = Database::open(&path).unwrap();
+        assert_eq!(user_version(&db), MIGRATIONS.len());
+        db.insert(&[record("ls", 1000)]).unwrap();
+        drop(db);
+
+        // Reopening runs nothing again and keeps what's there
+        let db = Database::open(&path).unwrap();
+        assert_eq!(user_version(&db), MIGRATIONS.len());
+        assert_eq!(commands(&db.query(&Filter::default()).unwrap()), ["ls"]);
+
+        db.conn
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        drop(db);
+        assert!(Database::open(&path).is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn imports_a_file_only_once() {
+        let path = temp_db("import");
+        let mut db = Database::open(&path).unwrap();
+        let jsonl = Path::new("/somewhere/history.jsonl");
+        assert!(!db.has_imported(jsonl).unwrap());
+        let records = [record("ls", 1000), record("pwd", 2000)];
+        db.import_once(jsonl, &records).unwrap();
+        assert!(db.has_imported(jsonl).unwrap());
+        db.import_once(jsonl, &records).unwrap();
+        assert_eq!(
+            commands(&db.query(&Filter::default()).unwrap()),
+            ["ls", "pwd"]
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn round_trips_records() {
+        let path = temp_db("round-trip");
+        let mut db = Database::open(&path).unwrap();
+        let full = Record {
+            duration: Some(3),
+            cwd: Some(PathBuf::from("/proj")),
+            exit_status: Some(-1),
+            hostname: Some("box".to_string()),
+            session: Some("s1".to_string()),
+            tags: vec!["deploy".to_string(), "with space, comma".to_string()],
+            ..record("make build", 1000)
+        };
+        db.insert(&[full, record("ls", 2000)]).unwrap();
+
+        let entries = db.query(&Filter::default()).unwrap();
+        let build = &entries[0];
+        assert_eq!(build.command, "make build");
+        assert_eq!(build.timestamp, Some(1000));
+        assert_eq!(build.duration, Some(3));
+        assert_eq!(build.cwd.as_deref(), Some(Path::new("/proj")));
+        assert_eq!(build.exit_status, Some(-1));
+        assert_eq!(build.hostname.as_deref(), Some("box"));
+        assert_eq!(build.session.as_deref(), Some("s1"));
+        let mut tags = build.tags.clone();
+        tags.sort();
+        assert_eq!(tags, ["deploy", "with space, comma"]);
+        assert!(entries[1].tags.is_empty());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn queries_by_each_condition() {
+        let path = temp_db("query");
+        let mut db = Database::open(&path).unwrap();
+        let entry = |command, timestamp, shell, cwd: &str, exit_status| Record {
+            shell,
+            cwd: Some(PathBuf::from(cwd)),
+            exit_status: Some(exit_status),
+            ..record(command, timestamp)
+        };
+        db.insert(&[
+            entry("one", 1000, Shell::Bash, "/a", 0),
+            entry("two", 2000, Shell::Zsh, "/b", 1),
+            entry("three", 3000, Shell::Fish, "/a", 1),
+        ])
+        .unwrap();
+
+        let cases = [
+            (Filter::default(), vec!["one", "two", "three"]),
+            (
+                Filter {
+                    time_range: TimeRange {
+                        since: Some(2000),
+                        until: None,
+                    },
+                    ..Filter::default()
+                },
+                vec!["two", "three"],
+            ),
+            (
+                Filter {
+                    time_range: TimeRange {
+                        since: None,
+                        until: Some(2000),
+                    },
+                    ..Filter::default()
+                },
+                vec!["one", "two"],
+            ),
+            (
+                Filter {
+                    shell: Some(Shell::Zsh),
+                    ..Filter::default()
+                },
+                vec!["two"],
+            ),
+            (
+                Filter {
+                    cwd: Some(PathBuf::from("/a")),
+                    ..Filter::default()
+                },
+                vec!["one", "three"],
+            ),
+            (
+                Filter {
+                    exit_status: Some(1),
+                    ..Filter::default()
+                },
+                vec!["two", "three"],
+            ),
+            (
+                Filter {
+                    cwd: Some(PathBuf::from("/a")),
+                    exit_status: Some(1),
+                    ..Filter::default()
+                },
+                vec!["three"],
+            ),
+        ];
+        for (filter, expected) in cases {
+            assert_eq!(
+                commands(&db.query(&filter).unwrap()),
+                expected,
+                "filter: {filter:?}"
+            );
+        }
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn parses_tags() {
+        assert_eq!(parse_tag("deploy"), Ok("deploy".to_string()));
+        assert!(parse_tag("a\x1fb").is_err());
+    }
+}
@@ -0,0 +1,293 @@
+use color_eyre::Report;
+use std::env;
+use std::fmt;
+use std::mem;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// How a selected history entry gets run.
+pub enum Executor {
+    /// `<path> -c <command>`, optionally with `-i` so the user's rc files
+    /// (and therefore aliases and functions) are loaded first
+    Shell { path: PathBuf, interactive: bool },
+    /// Split the command into words and exec the first one directly
+    Direct,
+}
+
+pub fn default_shell() -> PathBuf {
+    env::var_os("SHELL")
+        .filter(|shell| !shell.is_empty())
+        .map_or_else(|| PathBuf::from("/bin/sh"), PathBuf::from)
+}
+
+/// Replaces the current process with `command`. Only returns on failure.
+pub fn exec(command: &str, executor: &Executor) -> Report {
+    match executor {
+        Executor::Shell { path, interactive } => {
+            let mut cmd = Command::new(path);
+            if *interactive {
+                cmd.arg("-i");
+            }
+            cmd.arg("-c").arg(command).exec().into()
+        }
+        Executor::Direct => match parse_command_string(command) {
+            Ok((com, args)) => Command::new(com).args(args).exec().into(),
+            Err(err) => err.into(),
+        },
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    UnterminatedSingleQuote,
+    UnterminatedDoubleQuote,
+    TrailingBackslash,
+    /// The command uses syntax only a shell can interpret, e.g. `|` or `$`
+    NeedsShell(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "command is empty"),
+            ParseError::UnterminatedSingleQuote => write!(f, "unterminated single quote"),
+            ParseError::UnterminatedDoubleQuote => write!(f, "unterminated double quote"),
+            ParseError::TrailingBackslash => write!(f, "command ends with a backslash"),
+            ParseError::NeedsShell(what) => write!(
+                f,
+                "{what} needs a shell to run; drop --direct to run it through one"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+const KEYWORDS: &[&str] = &[
+    "!", "{", "case", "for", "function", "if", "select", "until", "while",
+];
+
+/// Splits a command line into a program and its arguments the way a POSIX
+/// shell would, without performing any expansion.
+///
+/// Anything that would need a shell to mean what it says (operators,
+/// expansions, globs, keywords, assignments) is refused rather than passed on
+/// literally.
+pub fn parse_command_string(input: &str) -> Result<(String, Vec<String>), ParseError> {
+    let mut words = split_words(input)?.into_iter();
+    let program = words.next().ok_or(ParseError::Empty)?;
+
+    if KEYWORDS.contains(&program.as_str()) {
+        return Err(ParseError::NeedsShell(format!("the `{program}` keyword")));
+    }
+    if let Some((name, _)) = program.split_once('=')
+        && !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(ParseError::NeedsShell("a variable assignment".to_string()));
+    }
+
+    Ok((program, words.collect()))
+}
+
+fn split_words(input: &str) -> Result<Vec<String>, ParseError> {
+    let needs_shell = |c: char| ParseError::NeedsShell(format!("`{c}`"));
+    let mut words = Vec::new();
+    let mut current = String::new();
+    // Separate from `current.is_empty()` so that `''` is still a word
+    let mut in_word = false;
+    // Unquoted `{`s still open in this word
+    let mut braces: usize = 0;
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => {
+                if in_word {
+                    words.push(mem::take(&mut current));
+                    in_word = false;
+                }
+                braces = 0;
+            }
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => current.push(c),
+                        None => return Err(ParseError::UnterminatedSingleQuote),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        // Inside double quotes only these are escapable
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('$' | '`' | '"' | '\\')) => current.push(c),
+                            Some('\n') => {}
+                            Some(c) => {
+                                current.push('\\');
+                                current.push(c);
+                            }
+                            None => return Err(ParseError::UnterminatedDoubleQuote),
+                        },
+                        Some(c @ ('$' | '`')) => return Err(needs_shell(c)),
+                        Some(c) => current.push(c),
+                        None => return Err(ParseError::UnterminatedDoubleQuote),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                // Line continuation
+                Some('\n') => {}
+                Some(c) => {
+                    current.push(c);
+                    in_word = true;
+                }
+                None => return Err(ParseError::TrailingBackslash),
+            },
+            '#' if !in_word => break,
+            '~' if !in_word => return Err(needs_shell(c)),
+            '|' | '&' | ';' | '<' | '>' | '(' | ')' | '$' | '`' | '*' | '?' | '[' => {
+                return Err(needs_shell(c));
+            }
+            // `{a,b}` and `{1..3}`; a lone `{}` (as in `find -exec`) is literal
+            ',' if braces > 0 => {
+                return Err(ParseError::NeedsShell("brace expansion".to_string()));
+            }
+            '.' if braces > 0 && current.ends_with('.') => {
+                return Err(ParseError::NeedsShell("brace expansion".to_string()));
+            }
+            _ => {
+                match c {
+                    '{' => braces += 1,
+                    '}' => braces = braces.saturating_sub(1),
+                    _ => {}
+                }
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+
+    if in_word {
+        words.push(current);
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(input: &str) -> Result<Vec<String>, ParseError> {
+        parse_command_string(input).map(|(program, args)| {
+            let mut words = vec![program];
+            words.extend(args);
+            words
+        })
+    }
+
+    #[test]
+    fn splits_like_a_posix_shell() {
+        let cases: &[(&str, &[&str])] = &[
+            ("ls", &["ls"]),
+            ("ls -la /tmp", &["ls", "-la", "/tmp"]),
+            ("  ls   -la  ", &["ls", "-la"]),
+            ("ls\t-la\n/tmp", &["ls", "-la", "/tmp"]),
+            ("echo 'hello world'", &["echo", "hello world"]),
+            ("echo \"hello world\"", &["echo", "hello world"]),
+            ("echo 'a \"b\" c'", &["echo", "a \"b\" c"]),
+            ("echo \"it's\"", &["echo", "it's"]),
+            ("echo 'a\\b'", &["echo", "a\\b"]),
+            ("echo \"a\\\"b\"", &["echo", "a\"b"]),
+            ("echo \"a\\\\b\"", &["echo", "a\\b"]),
+            ("echo \"a\\$b\"", &["echo", "a$b"]),
+            ("echo \"a\\nb\"", &["echo", "a\\nb"]),
+            ("echo a\\ b", &["echo", "a b"]),
+            ("echo \\'x\\'", &["echo", "'x'"]),
+            ("echo \\|", &["echo", "|"]),
+            ("echo a\"b\"'c'd", &["echo", "abcd"]),
+            ("echo '' \"\"", &["echo", "", ""]),
+            ("echo '$HOME' '*'", &["echo", "$HOME", "*"]),
+            ("echo one \\\ntwo", &["echo", "one", "two"]),
+            (
+                "git commit -m 'fix: a; b | c'",
+                &["git", "commit", "-m", "fix: a; b | c"],
+            ),
+            ("echo a#b # comment", &["echo", "a#b"]),
+            ("echo a~b", &["echo", "a~b"]),
+            ("env FOO=bar make", &["env", "FOO=bar", "make"]),
+            ("./run=fast", &["./run=fast"]),
+            (
+                "find . -exec rm {} +",
+                &["find", ".", "-exec", "rm", "{}", "+"],
+            ),
+            ("echo '{a,b}' \\{a,b}", &["echo", "{a,b}", "{a,b}"]),
+            ("echo {a} b,c ..", &["echo", "{a}", "b,c", ".."]),
+        ];
+
+        for (input, expected) in cases {
+            let expected: Vec<String> = expected.iter().map(|s| s.to_string()).collect();
+            assert_eq!(words(input), Ok(expected), "input: {input:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let cases: &[(&str, ParseError)] = &[
+            ("", ParseError::Empty),
+            ("   \t ", ParseError::Empty),
+            ("# just a comment", ParseError::Empty),
+            ("echo 'oops", ParseError::UnterminatedSingleQuote),
+            ("echo \"oops", ParseError::UnterminatedDoubleQuote),
+            ("echo \"oops\\", ParseError::UnterminatedDoubleQuote),
+            ("echo oops\\", ParseError::TrailingBackslash),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(words(input).as_ref(), Err(expected), "input: {input:?}");
+        }
+    }
+
+    #[test]
+    fn refuses_shell_syntax() {
+        let cases: &[(&str, &str)] = &[
+            ("ls | grep x", "`|`"),
+            ("make && make install", "`&`"),
+            ("sleep 1 &", "`&`"),
+            ("cd /tmp; ls", "`;`"),
+            ("sort < in", "`<`"),
+            ("echo hi > out", "`>`"),
+            ("(cd /tmp)", "`(`"),
+            ("echo $HOME", "`$`"),
+            ("echo \"$HOME\"", "`$`"),
+            ("echo `date`", "```"),
+            ("ls *.rs", "`*`"),
+            ("ls file?.txt", "`?`"),
+            ("ls [ab].txt", "`[`"),
+            ("cd ~/src", "`~`"),
+            ("for i in 1 2\ndo echo hi\ndone", "the `for` keyword"),
+            ("if true\nthen ls\nfi", "the `if` keyword"),
+            ("FOO=bar make", "a variable assignment"),
+            ("cp file{,.bak}", "brace expansion"),
+            ("mkdir -p src/{bin,lib}", "brace expansion"),
+            ("echo {1..3}", "brace expansion"),
+            ("echo x{a,{b,c}}", "brace expansion"),
+        ];
+
+        for (input, what) in cases {
+            assert_eq!(
+                words(input),
+                Err(ParseError::NeedsShell(what.to_string())),
+                "input: {input:?}"
+            );
+        }
+    }
+}
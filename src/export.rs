@@ -0,0 +1,358 @@
+//! Writing history back out, for `iff export` and `iff search`.
+
+use crate::history::{self, HistoryEntry, Shell};
+use clap::ValueEnum;
+use color_eyre::Result;
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// One JSON object per entry, with every known field
+    Jsonl,
+    /// A header row, then one row per entry; tags and paths are joined with ';'
+    Csv,
+    /// A bash history file with `#<timestamp>` lines
+    Bash,
+    /// A zsh extended history file
+    Zsh,
+    /// A fish history file
+    Fish,
+}
+
+/// An entry as written to JSON. Unlike [`crate::store::Record`] it carries
+/// what iff worked out while merging, like the source and run count.
+#[derive(Serialize)]
+pub struct Line<'a> {
+    command: &'a str,
+    shell: Shell,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cwd: Option<&'a Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_status: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hostname: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tags: &'a [String],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    paths: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<&'a Path>,
+    run_count: usize,
+}
+
+impl<'a> From<&'a HistoryEntry> for Line<'a> {
+    fn from(entry: &'a HistoryEntry) -> Self {
+        Line {
+            command: &entry.command,
+            shell: entry.shell,
+            timestamp: entry.timestamp,
+            duration: entry.duration,
+            cwd: entry.cwd.as_deref(),
+            exit_status: entry.exit_status,
+            hostname: entry.hostname.as_deref(),
+            session: entry.session.as_deref(),
+            tags: &entry.tags,
+            paths: &entry.paths,
+            source: entry.source.as_deref(),
+            run_count: entry.run_count,
+        }
+    }
+}
+
+/// A result of `iff search --json`.
+#[derive(Serialize)]
+pub struct Found<'a> {
+    /// Position in the merged history, newest first
+    pub index: usize,
+    pub score: i64,
+    /// Char offsets into the command that the query matched
+    pub positions: &'a [usize],
+    #[serde(flatten)]
+    pub entry: Line<'a>,
+}
+
+/// Writes `entries`, which should be oldest first as shells expect.
+pub fn write(out: &mut impl Write, entries: &[&HistoryEntry], format: Format) -> Result<()> {
+    match format {
+        Format::Jsonl => {
+            for &entry in entries {
+                serde_json::to_writer(&mut *out, &Line::from(entry))?;
+                writeln!(out)?;
+            }
+        }
+        Format::Csv => write_csv(out, entries)?,
+        Format::Bash => {
+            // Once there's a `#<timestamp>` line, bash reads everything up to
+            // the next one as a single command, so every entry needs one.
+            // Those without a time of their own borrow the one before them
+            // (or the first, at the start)
+            let mut last_seen = entries.iter().find_map(|entry| entry.timestamp);
+            for entry in entries {
+                last_seen = entry.timestamp.or(last_seen);
+                if let Some(ts) = last_seen {
+                    writeln!(out, "#{ts}")?;
+                }
+                writeln!(out, "{}", entry.command)?;
+            }
+        }
+        Format::Zsh => {
+            // A command ending in a backslash can't be written faithfully:
+            // zsh (and iff) read that as the newline inside a multi-line
+            // command, joining it to the entry after it
+            let mut last_seen = entries.iter().find_map(|entry| entry.timestamp);
+            for entry in entries {
+                last_seen = entry.timestamp.or(last_seen);
+                let mut line = Vec::new();
+                // Without a timestamp zsh would read a header as part of the
+                // command, so those entries are written plain. A command that
+                // looks like a header itself borrows a time, as for bash
+                let header_like = history::parse_zsh_header(&entry.command).is_some();
+                if let Some(ts) = entry.timestamp.or(last_seen.filter(|_| header_like)) {
+                    write!(line, ": {ts}:{};", entry.duration.unwrap_or(0))?;
+                } else if header_like {
+                    write!(line, ": 0:0;")?;
+                }
+                line.extend(metafy(&entry.command.replace('\n', "\\\n")));
+                line.push(b'\n');
+                out.write_all(&line)?;
+            }
+        }
+        Format::Fish => {
+            for entry in entries {
+                writeln!(out, "- cmd: {}", escape_fish(&entry.command))?;
+                if let Some(ts) = entry.timestamp {
+                    writeln!(out, "  when: {ts}")?;
+                }
+                if !entry.paths.is_empty() {
+                    writeln!(out, "  paths:")?;
+                    for path in &entry.paths {
+                        writeln!(out, "    - {}", escape_fish(path))?;
+                    }
+                }
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn write_csv(out: &mut impl Write, entries: &[&HistoryEntry]) -> Result<()> {
+    let mut csv = csv::Writer::from_writer(out);
+    csv.write_record([
+        "command",
+        "shell",
+        "timestamp",
+        "duration",
+        "cwd",
+        "exit_status",
+        "hostname",
+        "session",
+        "tags",
+        "paths",
+        "source",
+        "run_count",
+    ])?;
+    let text = |value: Option<String>| value.unwrap_or_default();
+    for entry in entries {
+        csv.write_record([
+            entry.command.clone(),
+            entry.shell.name().to_string(),
+            text(entry.timestamp.map(|ts| ts.to_string())),
+            text(entry.duration.map(|secs| secs.to_string())),
+            text(entry.cwd.as_ref().map(|cwd| cwd.display().to_string())),
+            text(entry.exit_status.map(|status| status.to_string())),
+            text(entry.hostname.clone()),
+            text(entry.session.clone()),
+            entry.tags.join(";"),
+            entry.paths.join(";"),
+            text(entry.source.as_ref().map(|path| path.display().to_string())),
+            entry.run_count.to_string(),
+        ])?;
+    }
+    csv.flush()?;
+    Ok(())
+}
+
+/// The reverse of the unmetafying done when reading zsh history: bytes zsh
+/// treats as special become 0x83 followed by the byte XOR 32.
+fn metafy(s: &str) -> Vec<u8> {
+    const META: u8 = 0x83;
+    let mut out = Vec::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        if (META..=0xa2).contains(&b) {
+            out.push(META);
+            out.push(b ^ 32);
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+/// fish writes newlines as `\n` and backslashes as `\\`.
+fn escape_fish(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Oldest first, with a mix of what each format finds awkward.
+    fn entries() -> Vec<HistoryEntry> {
+        let entry = |command: &str, timestamp: Option<u64>| HistoryEntry {
+            timestamp,
+            duration: timestamp.map(|_| 3),
+            ..HistoryEntry::new(command.to_string(), Shell::Bash)
+        };
+        vec![
+            entry("ls -la", None),
+            entry("for i in 1 2; do\n  echo $i\ndone", Some(1700000000)),
+            entry(": notheader", None),
+            entry("echo 'héllo ♥' a\\b", Some(1700000100)),
+            HistoryEntry {
+                paths: vec!["notes.txt".to_string()],
+                ..entry("cp notes.txt backup/", Some(1700000200))
+            },
+            entry(": 123:0;echo not a header either", None),
+            entry("echo \\\\", Some(1700000300)),
+        ]
+    }
+
+    fn export(format: Format) -> Vec<u8> {
+        let entries = entries();
+        let entries: Vec<&HistoryEntry> = entries.iter().collect();
+        let mut out = Vec::new();
+        write(&mut out, &entries, format).unwrap();
+        out
+    }
+
+    fn commands(entries: &[HistoryEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.command.as_str()).collect()
+    }
+
+    fn timestamps(entries: &[HistoryEntry]) -> Vec<Option<u64>> {
+        entries.iter().map(|entry| entry.timestamp).collect()
+    }
+
+    #[test]
+    fn round_trips_through_shell_formats() {
+        let expected = entries();
+        for (format, shell) in [
+            (Format::Bash, Shell::Bash),
+            (Format::Zsh, Shell::Zsh),
+            (Format::Fish, Shell::Fish),
+        ] {
+            let (read, stats) = history::parse_history(export(format), shell);
+            assert_eq!(stats, history::DecodeStats::default(), "{format:?}");
+            let mut expected = commands(&expected);
+            if format == Format::Zsh {
+                // Read as a continuation; see the next test
+                expected[6] = "echo \\\n";
+            }
+            assert_eq!(commands(&read), expected, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn keeps_shell_format_metadata() {
+        let expected = entries();
+
+        // bash has nowhere to put a missing time, so those borrow a neighbour's
+        let (bash, _) = history::parse_history(export(Format::Bash), Shell::Bash);
+        assert_eq!(
+            timestamps(&bash),
+            [
+                Some(1700000000),
+                Some(1700000000),
+                Some(1700000000),
+                Some(1700000100),
+                Some(1700000200),
+                Some(1700000200),
+                Some(1700000300)
+            ]
+        );
+
+        // zsh too, but only for a command that would pass for a header
+        let (zsh, _) = history::parse_history(export(Format::Zsh), Shell::Zsh);
+        assert_eq!(
+            timestamps(&zsh),
+            [
+                None,
+                Some(1700000000),
+                None,
+                Some(1700000100),
+                Some(1700000200),
+                Some(1700000200),
+                Some(1700000300)
+            ]
+        );
+        let durations: Vec<_> = zsh.iter().map(|entry| entry.duration).collect();
+        assert_eq!(
+            durations,
+            [None, Some(3), None, Some(3), Some(3), Some(0), Some(3)]
+        );
+
+        let (fish, _) = history::parse_history(export(Format::Fish), Shell::Fish);
+        assert_eq!(timestamps(&fish), timestamps(&expected));
+        assert_eq!(fish[4].paths, ["notes.txt"]);
+    }
+
+    #[test]
+    fn round_trips_through_jsonl() {
+        let expected = entries();
+        let out = String::from_utf8(export(Format::Jsonl)).unwrap();
+        let lines: Vec<serde_json::Value> = out
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), expected.len());
+        for (line, entry) in lines.iter().zip(&expected) {
+            assert_eq!(line["command"], entry.command.as_str());
+            assert_eq!(line["timestamp"].as_u64(), entry.timestamp);
+            assert_eq!(line["duration"].as_u64(), entry.duration);
+            assert_eq!(line["run_count"], 1);
+        }
+        assert_eq!(lines[4]["paths"], serde_json::json!(["notes.txt"]));
+    }
+
+    #[test]
+    fn round_trips_through_csv() {
+        let expected = entries();
+        let out = export(Format::Csv);
+        let mut reader = csv::Reader::from_reader(out.as_slice());
+        let headers = reader.headers().unwrap().clone();
+        assert_eq!(&headers[0], "command");
+        assert_eq!(&headers[2], "timestamp");
+        let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), expected.len());
+        for (row, entry) in rows.iter().zip(&expected) {
+            assert_eq!(&row[0], entry.command);
+            assert_eq!(row[2].parse().ok(), entry.timestamp);
+        }
+        assert_eq!(&rows[4][9], "notes.txt");
+    }
+
+    #[test]
+    fn zsh_joins_a_trailing_backslash_to_the_next_entry() {
+        let entries = [
+            HistoryEntry::new("echo \\\\".to_string(), Shell::Zsh),
+            HistoryEntry::new(": 1:0;ls".to_string(), Shell::Zsh),
+        ];
+        let entries: Vec<&HistoryEntry> = entries.iter().collect();
+        let mut out = Vec::new();
+        write(&mut out, &entries, Format::Zsh).unwrap();
+        assert_eq!(out, b"echo \\\\\n: 0:0;: 1:0;ls\n");
+
+        let (read, _) = history::parse_history(out, Shell::Zsh);
+        assert_eq!(commands(&read), ["echo \\\n: 0:0;: 1:0;ls"]);
+    }
+}
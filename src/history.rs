@@ -0,0 +1,1289 @@
+use crate::config::Dirs;
+use crate::store::{RunIndex, Store};
+use clap::ValueEnum;
+use color_eyre::Result;
+use color_eyre::eyre::bail;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    pub fn name(self) -> &'static str {
+        match self {
+            Shell::Bash => "bash",
+            Shell::Zsh => "zsh",
+            Shell::Fish => "fish",
+        }
+    }
+
+    /// Guesses the shell from a history file name such as `.zsh_history`,
+    /// or its directory for layouts like `~/.local/state/zsh/history`.
+    fn from_file_name(path: &Path) -> Option<Self> {
+        path.iter().rev().take(2).find_map(|part| {
+            let name = part.to_string_lossy().to_lowercase();
+            if name.contains("zsh") || name == ".zhistory" || name == ".histfile" {
+                Some(Shell::Zsh)
+            } else if name.contains("bash") {
+                Some(Shell::Bash)
+            } else if name.contains("fish") {
+                Some(Shell::Fish)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The shell named by `$SHELL`, if it's one we understand.
+    pub fn from_env() -> Option<Self> {
+        Self::from_path(Path::new(&env::var_os("SHELL")?))
+    }
+
+    fn from_path(shell: &Path) -> Option<Self> {
+        match shell.file_name()?.to_str()? {
+            "bash" => Some(Shell::Bash),
+            "zsh" => Some(Shell::Zsh),
+            "fish" => Some(Shell::Fish),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    pub command: String,
+    pub shell: Shell,
+    /// Unix time the command was started, when the history file records it
+    pub timestamp: Option<u64>,
+    /// How long the command ran for, in seconds
+    pub duration: Option<u64>,
+    /// Paths fish recorded as arguments of the command
+    pub paths: Vec<String>,
+    /// Directory the command was run in, when recorded
+    pub cwd: Option<PathBuf>,
+    pub exit_status: Option<i32>,
+    pub hostname: Option<String>,
+    /// Identifies the shell session the command was run in
+    pub session: Option<String>,
+    pub tags: Vec<String>,
+    /// The history file this entry came from
+    pub source: Option<Rc<Path>>,
+    /// How many times the command appears across all history, counting this
+    /// one
+    pub run_count: usize,
+}
+
+impl HistoryEntry {
+    pub fn new(command: String, shell: Shell) -> Self {
+        Self {
+            command,
+            shell,
+            timestamp: None,
+            duration: None,
+            paths: Vec::new(),
+            cwd: None,
+            exit_status: None,
+            hostname: None,
+            session: None,
+            tags: Vec::new(),
+            source: None,
+            run_count: 1,
+        }
+    }
+
+    /// Whether it has any of what only iff's store records.
+    fn has_recorded_metadata(&self) -> bool {
+        self.cwd.is_some()
+            || self.exit_status.is_some()
+            || self.hostname.is_some()
+            || self.session.is_some()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HistorySource {
+    pub path: PathBuf,
+    pub shell: Shell,
+}
+
+/// Finds the history files to read.
+///
+/// Explicit `files` win over discovery; otherwise `$HISTFILE`, XDG state/data
+/// dirs and the usual framework locations are searched. `shell` restricts
+/// discovery to one shell and forces the format of explicit files.
+pub fn discover_sources(files: &[PathBuf], shell: Option<Shell>) -> Result<Vec<HistorySource>> {
+    discover_sources_in(files, shell, |var| env::var_os(var))
+}
+
+/// [`discover_sources`], with `var` looking up the environment variables.
+fn discover_sources_in(
+    files: &[PathBuf],
+    shell: Option<Shell>,
+    var: impl Fn(&str) -> Option<OsString>,
+) -> Result<Vec<HistorySource>> {
+    let shell_from_env = || Shell::from_path(Path::new(&var("SHELL")?));
+    if !files.is_empty() {
+        return files
+            .iter()
+            .map(|path| {
+                if !path.exists() {
+                    bail!("history file {} does not exist", path.display());
+                }
+                let shell = shell
+                    .or_else(|| Shell::from_file_name(path))
+                    .or_else(shell_from_env)
+                    .unwrap_or(Shell::Bash);
+                Ok(HistorySource {
+                    path: path.clone(),
+                    shell,
+                })
+            })
+            .collect();
+    }
+
+    let mut candidates = Vec::new();
+    // Shells don't export it, but the `iff init` widgets pass it along
+    if let Some(histfile) = var("HISTFILE") {
+        let path = PathBuf::from(histfile);
+        if let Some(guess) = Shell::from_file_name(&path).or_else(shell_from_env) {
+            candidates.push(HistorySource { path, shell: guess });
+        }
+    }
+    candidates.extend(default_locations(&var));
+
+    let mut seen = HashSet::new();
+    Ok(candidates
+        .into_iter()
+        .filter(|source| shell.is_none_or(|shell| source.shell == shell))
+        .filter(|source| source.path.is_file())
+        .filter(|source| seen.insert(fs::canonicalize(&source.path).ok()))
+        .collect())
+}
+
+fn default_locations(var: &impl Fn(&str) -> Option<OsString>) -> Vec<HistorySource> {
+    let Dirs {
+        home,
+        data: data_home,
+        state: state_home,
+        ..
+    } = Dirs::from_vars(var);
+    let zdotdir = var("ZDOTDIR").map_or_else(|| home.clone(), PathBuf::from);
+
+    let zsh = [
+        // oh-my-zsh and plain zsh
+        zdotdir.join(".zsh_history"),
+        home.join(".zsh_history"),
+        // prezto
+        zdotdir.join(".zhistory"),
+        // zsh-newuser-install
+        zdotdir.join(".histfile"),
+        state_home.join("zsh/history"),
+        data_home.join("zsh/history"),
+    ];
+    let bash = [
+        home.join(".bash_history"),
+        state_home.join("bash/history"),
+        data_home.join("bash/history"),
+    ];
+    let fish = var("fish_history")
+        .filter(|name| !name.is_empty() && name != "default")
+        .map_or_else(
+            || data_home.join("fish/fish_history"),
+            |name| {
+                let mut file = name;
+                file.push("_history");
+                data_home.join("fish").join(file)
+            },
+        );
+
+    zsh.into_iter()
+        .map(|path| HistorySource {
+            path,
+            shell: Shell::Zsh,
+        })
+        .chain(bash.into_iter().map(|path| HistorySource {
+            path,
+            shell: Shell::Bash,
+        }))
+        .chain([HistorySource {
+            path: fish,
+            shell: Shell::Fish,
+        }])
+        .collect()
+}
+
+/// The merged history plus anything worth telling the user about how it was
+/// read, e.g. undecodable lines or unreadable files.
+pub struct LoadedHistory {
+    pub entries: Vec<HistoryEntry>,
+    pub warnings: Vec<String>,
+}
+
+/// Loads every given history file, plus iff's own recorded history from
+/// `store` if it exists, and merges them, most recent first. Runs `filter`
+/// doesn't allow are dropped before merging, whichever source they come
+/// from, so a command re-run since (or elsewhere) still shows up for the run
+/// that matches; the store may drop them itself.
+///
+/// A file that can't be read or decoded never aborts loading; it is reported
+/// in `warnings` instead.
+pub fn load_history(
+    sources: &[HistorySource],
+    store: Option<&Store>,
+    filter: &Filter,
+) -> LoadedHistory {
+    let mut loaded = Vec::new();
+    let mut warnings = Vec::new();
+
+    let store = store.filter(|store| store.exists());
+    let mut recorded = Vec::new();
+    if let Some(store) = store {
+        match store.load(filter) {
+            Ok((entries, skipped)) => {
+                if skipped > 0 {
+                    warnings.push(format!("{}: {skipped} lines skipped", store.path.display()));
+                }
+                recorded = entries;
+                recorded.retain(|entry| filter.allows(entry));
+            }
+            Err(err) => warnings.push(format!("{}: {err}", store.path.display())),
+        }
+    }
+    let recorded_runs = RunIndex::new(&recorded);
+    // How many recorded runs of each command the files have yet to be
+    // matched against
+    let mut unmatched: HashMap<String, HashMap<Shell, usize>> = HashMap::new();
+    for entry in &recorded {
+        *unmatched
+            .entry(entry.command.clone())
+            .or_default()
+            .entry(entry.shell)
+            .or_default() += 1;
+    }
+    let mut match_run = |entry: &HistoryEntry| {
+        let count = unmatched
+            .get_mut(&entry.command)
+            .and_then(|shells| shells.get_mut(&entry.shell));
+        match count {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                true
+            }
+            _ => false,
+        }
+    };
+
+    for source in sources {
+        match read_history_file(&source.path, source.shell) {
+            Ok((mut entries, stats)) => {
+                if stats.recovered > 0 || stats.skipped > 0 {
+                    warnings.push(format!(
+                        "{}: {} lines recovered, {} skipped (invalid UTF-8)",
+                        source.path.display(),
+                        stats.recovered,
+                        stats.skipped
+                    ));
+                }
+                // The store's copy of a run has more metadata, so it wins
+                entries.retain(|entry| {
+                    let Some(ts) = entry.timestamp else {
+                        return true;
+                    };
+                    let recorded = recorded_runs.contains(&entry.command, ts);
+                    if recorded {
+                        match_run(entry);
+                    }
+                    !recorded
+                });
+                // Entries without a timestamp can't be matched by time, so
+                // the newest ones are taken to be the runs the store has left
+                let mut keep: Vec<bool> = entries
+                    .iter()
+                    .rev()
+                    .map(|entry| entry.timestamp.is_some() || !match_run(entry))
+                    .collect();
+                entries.retain(|_| keep.pop().unwrap_or(true));
+                entries.retain(|entry| filter.allows(entry));
+                set_source(&mut entries, &source.path);
+                loaded.push((file_mtime(&source.path), entries));
+            }
+            Err(err) => warnings.push(format!("{}: {err}", source.path.display())),
+        }
+    }
+
+    if let Some(store) = store
+        && !recorded.is_empty()
+    {
+        set_source(&mut recorded, &store.path);
+        loaded.push((file_mtime(&store.path), recorded));
+    }
+
+    LoadedHistory {
+        entries: merge(loaded),
+        warnings,
+    }
+}
+
+fn set_source(entries: &mut [HistoryEntry], path: &Path) {
+    let path: Rc<Path> = Rc::from(path);
+    for entry in entries {
+        entry.source = Some(Rc::clone(&path));
+    }
+}
+
+/// Lines that weren't valid UTF-8.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DecodeStats {
+    /// Kept with the bad bytes replaced
+    pub recovered: usize,
+    /// Dropped as there was nothing left of them
+    pub skipped: usize,
+}
+
+fn read_history_file(path: &Path, shell: Shell) -> Result<(Vec<HistoryEntry>, DecodeStats)> {
+    Ok(parse_history(fs::read(path)?, shell))
+}
+
+/// Parses the raw contents of a history file written by `shell`.
+pub fn parse_history(mut bytes: Vec<u8>, shell: Shell) -> (Vec<HistoryEntry>, DecodeStats) {
+    if shell == Shell::Zsh {
+        bytes = unmetafy(&bytes);
+    }
+    let (content, stats) = decode_lines(&bytes);
+    let entries = match shell {
+        Shell::Bash => parse_bash(&content),
+        Shell::Zsh => parse_zsh(&content),
+        Shell::Fish => parse_fish(&content),
+    };
+    (entries, stats)
+}
+
+/// zsh stores bytes it considers special as 0x83 ("Meta") followed by the
+/// byte XOR 32. Non-ASCII text is full of them, so undo that first.
+fn unmetafy(bytes: &[u8]) -> Vec<u8> {
+    const META: u8 = 0x83;
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut iter = bytes.iter();
+    while let Some(&b) = iter.next() {
+        if b == META {
+            if let Some(&next) = iter.next() {
+                out.push(next ^ 32);
+            }
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+/// Decodes the file line by line so one bad line doesn't sink the rest.
+/// Invalid sequences are replaced with U+FFFD; lines with nothing left but
+/// replacement characters are dropped.
+fn decode_lines(bytes: &[u8]) -> (String, DecodeStats) {
+    let mut stats = DecodeStats::default();
+    let mut lines = Vec::new();
+    for raw in bytes.split(|&b| b == b'\n') {
+        match std::str::from_utf8(raw) {
+            Ok(line) => lines.push(line.to_string()),
+            Err(_) => {
+                let line = String::from_utf8_lossy(raw);
+                if line
+                    .chars()
+                    .all(|c| c == char::REPLACEMENT_CHARACTER || c.is_whitespace())
+                {
+                    stats.skipped += 1;
+                } else {
+                    stats.recovered += 1;
+                    lines.push(line.into_owned());
+                }
+            }
+        }
+    }
+    (lines.join("\n"), stats)
+}
+
+/// Parses bash history. With `HISTTIMEFORMAT` set, each entry is preceded by
+/// a `#<timestamp>` line, and everything up to the next one is a single
+/// (possibly multi-line) command run at that time. Without those markers every
+/// line stands alone, as do the lines before the first marker when
+/// `HISTTIMEFORMAT` was only turned on later.
+fn parse_bash(content: &str) -> Vec<HistoryEntry> {
+    let has_markers = content.lines().any(|line| bash_timestamp(line).is_some());
+    if !has_markers {
+        return content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| HistoryEntry::new(line.to_string(), Shell::Bash))
+            .collect();
+    }
+
+    let mut entries = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    let mut timestamp = None;
+    for line in content.lines() {
+        if let Some(ts) = bash_timestamp(line) {
+            entries.extend(bash_entry(&current, timestamp));
+            current.clear();
+            timestamp = Some(ts);
+        } else if timestamp.is_none() {
+            entries.extend(bash_entry(&[line], None));
+        } else {
+            current.push(line);
+        }
+    }
+    entries.extend(bash_entry(&current, timestamp));
+    entries
+}
+
+fn bash_entry(lines: &[&str], timestamp: Option<u64>) -> Option<HistoryEntry> {
+    let command = lines.join("\n");
+    if command.trim().is_empty() {
+        return None;
+    }
+    Some(HistoryEntry {
+        timestamp,
+        ..HistoryEntry::new(command, Shell::Bash)
+    })
+}
+
+/// The timestamp of a `#1712345678` comment line written by bash.
+fn bash_timestamp(line: &str) -> Option<u64> {
+    let digits = line.strip_prefix('#')?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+fn parse_zsh(content: &str) -> Vec<HistoryEntry> {
+    zsh_logical_lines(content)
+        .into_iter()
+        .map(|line| match parse_zsh_header(&line) {
+            Some((timestamp, elapsed, command)) => HistoryEntry {
+                timestamp: Some(timestamp),
+                duration: Some(elapsed),
+                ..HistoryEntry::new(command.to_string(), Shell::Zsh)
+            },
+            // Plain history, or a command that merely starts with ':'
+            None => HistoryEntry::new(line, Shell::Zsh),
+        })
+        .filter(|entry| !entry.command.trim().is_empty())
+        .collect()
+}
+
+/// zsh writes the newlines inside a command as a backslash at the end of the
+/// physical line; glue those back together.
+fn zsh_logical_lines(content: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current: Option<String> = None;
+    for line in content.lines() {
+        let buf = current.get_or_insert_with(String::new);
+        match line.strip_suffix('\\') {
+            Some(head) => {
+                buf.push_str(head);
+                buf.push('\n');
+            }
+            None => {
+                buf.push_str(line);
+                lines.extend(current.take());
+            }
+        }
+    }
+    lines.extend(current);
+    lines
+}
+
+/// Splits an extended history line, ": <start>:<elapsed>;<command>", into
+/// its parts. Returns `None` for anything that isn't exactly that shape.
+pub fn parse_zsh_header(line: &str) -> Option<(u64, u64, &str)> {
+    let rest = line.strip_prefix(": ")?;
+    let (start, rest) = rest.split_once(':')?;
+    let (elapsed, command) = rest.split_once(';')?;
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !is_number(start) || !is_number(elapsed) {
+        return None;
+    }
+    Some((start.parse().ok()?, elapsed.parse().ok()?, command))
+}
+
+/// Parses fish's YAML-like history:
+///
+/// ```text
+/// - cmd: cp notes.txt backup/
+///   when: 1712345678
+///   paths:
+///     - notes.txt
+/// ```
+fn parse_fish(content: &str) -> Vec<HistoryEntry> {
+    let mut entries: Vec<HistoryEntry> = Vec::new();
+    let mut in_paths = false;
+
+    for line in content.lines() {
+        if let Some(cmd) = line.strip_prefix("- cmd: ") {
+            entries.push(HistoryEntry::new(unescape_fish(cmd), Shell::Fish));
+            in_paths = false;
+            continue;
+        }
+        let Some(entry) = entries.last_mut() else {
+            continue;
+        };
+        if let Some(when) = line.strip_prefix("  when: ") {
+            entry.timestamp = when.trim().parse().ok();
+            in_paths = false;
+        } else if line == "  paths:" {
+            in_paths = true;
+        } else if let Some(path) = line.strip_prefix("    - ")
+            && in_paths
+        {
+            entry.paths.push(unescape_fish(path));
+        } else {
+            in_paths = false;
+        }
+    }
+
+    entries.retain(|entry| !entry.command.trim().is_empty());
+    entries
+}
+
+/// Undoes fish's history escaping, where `\n` is a newline and `\\` a
+/// backslash. Anything else is kept verbatim.
+fn unescape_fish(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('\\') => out.push('\\'),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// An optional window of time that entries must fall in. Entries without a
+/// timestamp never match a bounded range.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimeRange {
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+}
+
+impl TimeRange {
+    pub fn contains(&self, entry: &HistoryEntry) -> bool {
+        if self.since.is_none() && self.until.is_none() {
+            return true;
+        }
+        entry.timestamp.is_some_and(|ts| {
+            self.since.is_none_or(|since| ts >= since) && self.until.is_none_or(|until| ts <= until)
+        })
+    }
+}
+
+/// Decides which loaded entries are shown at all.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub time_range: TimeRange,
+    /// Commands matching any of these are hidden
+    pub exclude: Vec<Regex>,
+    /// Only this shell's commands. Discovery already picks its files; this
+    /// covers iff's own history, which mixes shells
+    pub shell: Option<Shell>,
+    /// Only commands recorded as run in this directory
+    pub cwd: Option<PathBuf>,
+    /// Only commands recorded as exiting with this status
+    pub exit_status: Option<i32>,
+}
+
+impl Filter {
+    pub fn allows(&self, entry: &HistoryEntry) -> bool {
+        self.time_range.contains(entry)
+            && self.shell.is_none_or(|shell| entry.shell == shell)
+            && self
+                .cwd
+                .as_ref()
+                .is_none_or(|cwd| entry.cwd.as_ref() == Some(cwd))
+            && self
+                .exit_status
+                .is_none_or(|status| entry.exit_status == Some(status))
+            && !self.exclude.iter().any(|re| re.is_match(&entry.command))
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Parses a point in time given on the command line: a relative age like
+/// `30m`, `2d` or `1w`, a `YYYY-MM-DD` date (UTC midnight) or raw Unix seconds.
+pub fn parse_time(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(secs);
+    }
+    if let Some(unit) = s.chars().last().filter(char::is_ascii_alphabetic)
+        && let Ok(n) = s[..s.len() - 1].parse::<u64>()
+    {
+        let scale = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 7 * 86400,
+            _ => {
+                return Err(format!(
+                    "unknown time unit '{unit}', expected s, m, h, d or w"
+                ));
+            }
+        };
+        let secs = n
+            .checked_mul(scale)
+            .ok_or_else(|| format!("{s} is too long ago"))?;
+        return Ok(now().saturating_sub(secs));
+    }
+    let parts: Vec<&str> = s.split('-').collect();
+    if let [y, m, d] = parts[..]
+        && let (Ok(y), Ok(m), Ok(d)) = (y.parse::<i64>(), m.parse::<u32>(), d.parse::<u32>())
+        && (0..=9999).contains(&y)
+        && (1..=12).contains(&m)
+        && (1..=31).contains(&d)
+    {
+        let days = days_from_civil(y, m, d);
+        // Days past the end of the month would roll over into the next
+        if civil_from_days(days) != (y, m, d) {
+            return Err(format!("{s} isn't a valid date"));
+        }
+        return u64::try_from(days * 86400).map_err(|_| format!("{s} is before 1970"));
+    }
+    Err(format!(
+        "invalid time '{s}', expected e.g. 2d, 3h, 2024-05-01 or Unix seconds"
+    ))
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date (Howard Hinnant's
+/// algorithm).
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let m = i64::from(m);
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + i64::from(d) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Formats Unix seconds as `YYYY-MM-DD HH:MM UTC`.
+pub fn format_timestamp(ts: u64) -> String {
+    let days = i64::try_from(ts / 86400).unwrap_or(i64::MAX);
+    let (y, m, d) = civil_from_days(days);
+    let secs = ts % 86400;
+    format!(
+        "{y:04}-{m:02}-{d:02} {:02}:{:02} UTC",
+        secs / 3600,
+        secs % 3600 / 60
+    )
+}
+
+/// The inverse of [`days_from_civil`].
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    (y, m, d)
+}
+
+/// Formats a number of seconds compactly, e.g. `42s`, `3m`, `2h`, `5d`.
+pub fn format_seconds(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+fn file_mtime(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Merges per-file entry lists (each oldest first) into a single list ordered
+/// newest first, keeping only the most recent occurrence of each command
+/// along with how many times it was run.
+///
+/// Entries without a timestamp take the last known timestamp before them in
+/// the same file. Files with no timestamps at all use their mtime, so a plain
+/// bash history still interleaves with a timestamped zsh one.
+fn merge(sources: Vec<(u64, Vec<HistoryEntry>)>) -> Vec<HistoryEntry> {
+    let mut keyed = Vec::new();
+    for (mtime, entries) in sources {
+        let has_timestamps = entries.iter().any(|entry| entry.timestamp.is_some());
+        let mut last_seen = if has_timestamps { 0 } else { mtime };
+        for (seq, entry) in entries.into_iter().enumerate() {
+            if let Some(ts) = entry.timestamp {
+                last_seen = ts;
+            }
+            keyed.push((last_seen, seq, entry));
+        }
+    }
+
+    keyed.sort_by_key(|(ts, seq, _)| Reverse((*ts, *seq)));
+
+    let mut merged: Vec<HistoryEntry> = Vec::new();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for (_, _, entry) in keyed {
+        match seen.get(&entry.command) {
+            Some(&i) => {
+                let run_count = merged[i].run_count + entry.run_count;
+                // A bare copy from a history file shouldn't hide what the
+                // store knows about the command
+                if !merged[i].has_recorded_metadata() && entry.has_recorded_metadata() {
+                    merged[i] = entry;
+                }
+                merged[i].run_count = run_count;
+            }
+            None => {
+                seen.insert(entry.command.clone(), merged.len());
+                merged.push(entry);
+            }
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{Backend, Record};
+
+    /// A command and its timestamp.
+    type Parsed<'a> = (&'a str, Option<u64>);
+
+    /// A fresh, empty directory for one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("iff-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn commands(entries: &[HistoryEntry]) -> Vec<Parsed<'_>> {
+        entries
+            .iter()
+            .map(|entry| (entry.command.as_str(), entry.timestamp))
+            .collect()
+    }
+
+    #[test]
+    fn parses_bash_history() {
+        let cases: &[(&str, &[Parsed])] = &[
+            (
+                "ls -la\ngit status\n",
+                &[("ls -la", None), ("git status", None)],
+            ),
+            ("ls\n\n  \npwd", &[("ls", None), ("pwd", None)]),
+            (
+                "#1712345678\nls\n#1712345690\nfor i in 1 2; do\n  echo $i\ndone\n",
+                &[
+                    ("ls", Some(1712345678)),
+                    ("for i in 1 2; do\n  echo $i\ndone", Some(1712345690)),
+                ],
+            ),
+            // HISTTIMEFORMAT turned on after some history had built up
+            (
+                "ls -la\ngit status\ncargo build\n#1712345678\necho new\n",
+                &[
+                    ("ls -la", None),
+                    ("git status", None),
+                    ("cargo build", None),
+                    ("echo new", Some(1712345678)),
+                ],
+            ),
+            ("#1712345678\n#notes\n", &[("#notes", Some(1712345678))]),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(commands(&parse_bash(input)), *expected, "input: {input:?}");
+        }
+    }
+
+    #[test]
+    fn parses_zsh_history() {
+        let cases: &[(&str, &[Parsed])] = &[
+            (
+                ": 1712345678:0;ls -la\n: 1712345690:5;make\n",
+                &[("ls -la", Some(1712345678)), ("make", Some(1712345690))],
+            ),
+            ("ls\npwd\n", &[("ls", None), ("pwd", None)]),
+            // Newlines in a command are written as a trailing backslash
+            (
+                ": 1712345678:0;for i in 1 2; do\\\n  echo $i\\\ndone\n: 1712345690:0;ls\n",
+                &[
+                    ("for i in 1 2; do\n  echo $i\ndone", Some(1712345678)),
+                    ("ls", Some(1712345690)),
+                ],
+            ),
+            (
+                ": 1712345678:0;echo a;b\n",
+                &[("echo a;b", Some(1712345678))],
+            ),
+            // Commands that merely start with ':'
+            (
+                ": notheader\n: 12:x;no\n:1712345678:0;no\n",
+                &[
+                    (": notheader", None),
+                    (": 12:x;no", None),
+                    (":1712345678:0;no", None),
+                ],
+            ),
+            (": 1712345678:0;\n", &[]),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(commands(&parse_zsh(input)), *expected, "input: {input:?}");
+        }
+    }
+
+    #[test]
+    fn parses_zsh_headers() {
+        let cases = [
+            (": 1712345678:12;make", Some((1712345678, 12, "make"))),
+            (": 1712345678:0;", Some((1712345678, 0, ""))),
+            (": 1712345678:0;a;b", Some((1712345678, 0, "a;b"))),
+            (": 1712345678;make", None),
+            (": :0;make", None),
+            (": 17x:0;make", None),
+            (": 1712345678:-1;make", None),
+            ("1712345678:0;make", None),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse_zsh_header(input), expected, "input: {input:?}");
+        }
+    }
+
+    #[test]
+    fn parses_fish_history() {
+        let input = "- cmd: cp notes.txt backup/\n  when: 1712345678\n  paths:\n    - notes.txt\n    - backup/\n- cmd: echo a\\nb\\\\c\n  when: 1712345690\n- cmd: ls\n- cmd: \n  when: 1\n";
+        let entries = parse_fish(input);
+        assert_eq!(
+            commands(&entries),
+            [
+                ("cp notes.txt backup/", Some(1712345678)),
+                ("echo a\nb\\c", Some(1712345690)),
+                ("ls", None),
+            ]
+        );
+        assert_eq!(entries[0].paths, ["notes.txt", "backup/"]);
+        assert!(entries[1].paths.is_empty());
+    }
+
+    #[test]
+    fn unescapes_fish() {
+        let cases = [
+            ("plain", "plain"),
+            ("a\\nb", "a\nb"),
+            ("a\\\\b", "a\\b"),
+            ("a\\\\nb", "a\\nb"),
+            ("a\\tb", "a\\tb"),
+            ("trailing\\", "trailing\\"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(unescape_fish(input), expected, "input: {input:?}");
+        }
+    }
+
+    #[test]
+    fn unmetafies_zsh() {
+        let cases: &[(&[u8], &[u8])] = &[
+            (b"ls", b"ls"),
+            // "é" is C3 A9; zsh leaves both alone
+            ("é".as_bytes(), "é".as_bytes()),
+            // "♥" is E2 99 A5, and 0x99 gets escaped
+            (b"\xe2\x83\xb9\xa5", "♥".as_bytes()),
+            (b"\x83\xa3", b"\x83"),
+            // A dangling Meta at the end is dropped
+            (b"ab\x83", b"ab"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(unmetafy(input), *expected, "input: {input:?}");
+        }
+    }
+
+    #[test]
+    fn recovers_invalid_utf8_per_line() {
+        let (content, stats) = decode_lines(b"ls\nech\xffo hi\n\xff\xfe\npwd");
+        assert_eq!(content, "ls\nech\u{fffd}o hi\npwd");
+        assert_eq!(
+            stats,
+            DecodeStats {
+                recovered: 1,
+                skipped: 1
+            }
+        );
+
+        let (entries, stats) =
+            parse_history(b": 1:0;I \xe2\x83\xb9\xa5 zsh\n".to_vec(), Shell::Zsh);
+        assert_eq!(commands(&entries), [("I ♥ zsh", Some(1))]);
+        assert_eq!(stats, DecodeStats::default());
+    }
+
+    #[test]
+    fn parses_times() {
+        let cases = [
+            ("0", Ok(0)),
+            ("1712345678", Ok(1712345678)),
+            (" 1712345678 ", Ok(1712345678)),
+            ("1970-01-01", Ok(0)),
+            ("2024-05-01", Ok(1714521600)),
+            ("2000-02-29", Ok(951782400)),
+            ("2024-12-31", Ok(1735603200)),
+            ("1969-12-31", Err("1969-12-31 is before 1970".to_string())),
+            (
+                "2024-02-31",
+                Err("2024-02-31 isn't a valid date".to_string()),
+            ),
+            (
+                "2023-02-29",
+                Err("2023-02-29 isn't a valid date".to_string()),
+            ),
+            (
+                "2024-04-31",
+                Err("2024-04-31 isn't a valid date".to_string()),
+            ),
+            (
+                "18446744073709551615w",
+                Err("18446744073709551615w is too long ago".to_string()),
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse_time(input), expected, "input: {input:?}");
+        }
+
+        let now = now();
+        let relative = [
+            ("30s", 30),
+            ("5m", 300),
+            ("2h", 7200),
+            ("3d", 259200),
+            ("1w", 604800),
+        ];
+        for (input, age) in relative {
+            let ts = parse_time(input).unwrap();
+            // now() may tick over between the two calls
+            assert!(now - age <= ts && ts <= now - age + 1, "input: {input:?}");
+        }
+
+        for input in [
+            "",
+            "2d ago",
+            "3y",
+            "2024-13-01",
+            "2024-05-32",
+            "99999999999-01-01",
+            "yesterday",
+            "-5",
+        ] {
+            assert!(parse_time(input).is_err(), "input: {input:?}");
+        }
+    }
+
+    #[test]
+    fn converts_civil_dates() {
+        let cases = [
+            ((1970, 1, 1), 0),
+            ((1969, 12, 31), -1),
+            ((2000, 1, 1), 10957),
+            ((2000, 2, 29), 11016),
+            ((2000, 3, 1), 11017),
+            ((2024, 5, 1), 19844),
+            ((1900, 3, 1), -25508),
+        ];
+        for ((y, m, d), days) in cases {
+            assert_eq!(days_from_civil(y, m, d), days, "date: {y}-{m}-{d}");
+            assert_eq!(civil_from_days(days), (y, m, d), "days: {days}");
+        }
+        assert_eq!(format_timestamp(1714567890), "2024-05-01 12:51 UTC");
+    }
+
+    #[test]
+    fn discovers_sources() {
+        let home = temp_dir("discover");
+        for file in [
+            ".bash_history",
+            ".zsh_history",
+            ".local/share/fish/work_history",
+            ".local/share/fish/fish_history",
+            "custom/hist",
+        ] {
+            let path = home.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "ls\n").unwrap();
+        }
+        let discover = |shell, vars: &[(&str, PathBuf)]| {
+            let mut vars = vars.to_vec();
+            vars.push(("HOME", home.clone()));
+            let sources = discover_sources_in(&[], shell, |var| {
+                vars.iter()
+                    .find(|(name, _)| *name == var)
+                    .map(|(_, value)| value.clone().into_os_string())
+            })
+            .unwrap();
+            sources
+                .into_iter()
+                .map(|source| {
+                    let path = source.path.strip_prefix(&home).unwrap().to_path_buf();
+                    (path.display().to_string(), source.shell)
+                })
+                .collect::<Vec<_>>()
+        };
+        let found = |sources: &[(&str, Shell)]| {
+            sources
+                .iter()
+                .map(|&(path, shell)| (path.to_string(), shell))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            discover(None, &[]),
+            found(&[
+                (".zsh_history", Shell::Zsh),
+                (".bash_history", Shell::Bash),
+                (".local/share/fish/fish_history", Shell::Fish),
+            ])
+        );
+        assert_eq!(
+            discover(Some(Shell::Bash), &[]),
+            found(&[(".bash_history", Shell::Bash)])
+        );
+        // fish names the file after $fish_history
+        assert_eq!(
+            discover(
+                Some(Shell::Fish),
+                &[("fish_history", PathBuf::from("work"))]
+            ),
+            found(&[(".local/share/fish/work_history", Shell::Fish)])
+        );
+
+        // $HISTFILE comes first, its shell guessed from the name or $SHELL,
+        // and isn't listed twice when it's one of the usual files
+        assert_eq!(
+            discover(
+                None,
+                &[
+                    ("HISTFILE", home.join("custom/hist")),
+                    ("SHELL", PathBuf::from("/usr/bin/zsh")),
+                ]
+            ),
+            found(&[
+                ("custom/hist", Shell::Zsh),
+                (".zsh_history", Shell::Zsh),
+                (".bash_history", Shell::Bash),
+                (".local/share/fish/fish_history", Shell::Fish),
+            ])
+        );
+        assert_eq!(
+            discover(
+                Some(Shell::Zsh),
+                &[("HISTFILE", home.join("custom/../.zsh_history"))]
+            ),
+            found(&[("custom/../.zsh_history", Shell::Zsh)])
+        );
+        // With nothing to go on, $HISTFILE is left out
+        assert_eq!(
+            discover(Some(Shell::Zsh), &[("HISTFILE", home.join("custom/hist"))]),
+            found(&[(".zsh_history", Shell::Zsh)])
+        );
+        fs::remove_dir_all(home).unwrap();
+    }
+
+    #[test]
+    fn merges_sources_newest_first() {
+        let entry = |command: &str, shell, timestamp| HistoryEntry {
+            timestamp,
+            ..HistoryEntry::new(command.to_string(), shell)
+        };
+        // A plain bash file, so its modification time stands in for when
+        // its commands ran, and the file order for their order
+        let bash = vec![
+            entry("ls", Shell::Bash, None),
+            entry("make", Shell::Bash, None),
+            entry("git status", Shell::Bash, None),
+        ];
+        // zsh's times are its own; the file's is ignored
+        let zsh = vec![
+            entry("make", Shell::Zsh, Some(1000)),
+            entry("vim", Shell::Zsh, Some(2000)),
+            entry("ls", Shell::Zsh, Some(1200)),
+        ];
+        let merged = merge(vec![(1500, bash), (9999, zsh)]);
+        let summary: Vec<_> = merged
+            .iter()
+            .map(|entry| (entry.command.as_str(), entry.shell, entry.run_count))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("vim", Shell::Zsh, 1),
+                ("git status", Shell::Bash, 1),
+                ("make", Shell::Bash, 2),
+                ("ls", Shell::Bash, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn time_filters_each_run_before_merging() {
+        let dir = temp_dir("time-filter");
+        let path = dir.join(".bash_history");
+        fs::write(&path, "#1000\nmake old\n#1500\nls\n#2000000000\nmake old\n").unwrap();
+        let sources = [HistorySource {
+            path,
+            shell: Shell::Bash,
+        }];
+        let filter = Filter {
+            time_range: TimeRange {
+                since: None,
+                until: Some(1800),
+            },
+            ..Filter::default()
+        };
+
+        let loaded = load_history(&sources, None, &filter);
+        assert_eq!(
+            commands(&loaded.entries),
+            [("ls", Some(1500)), ("make old", Some(1000))]
+        );
+        assert_eq!(loaded.entries[1].run_count, 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn filters_store_runs_before_merging_with_either_backend() {
+        let dir = temp_dir("store-filter");
+        let record = |cwd: &str, exit_status, timestamp| Record {
+            command: "make".to_string(),
+            shell: Shell::Bash,
+            timestamp,
+            duration: None,
+            cwd: Some(PathBuf::from(cwd)),
+            exit_status: Some(exit_status),
+            hostname: None,
+            session: None,
+            tags: Vec::new(),
+        };
+        for (backend, file) in [
+            (Backend::Jsonl, "jsonl/history.jsonl"),
+            (Backend::Sqlite, "sqlite/history.db"),
+        ] {
+            // Apart, or the SQLite store would copy in the JSON Lines one
+            let store = Store {
+                backend,
+                path: dir.join(file),
+            };
+            store
+                .append(&[record("/a", 0, 1000), record("/b", 1, 2000)])
+                .unwrap();
+
+            let filter = Filter {
+                cwd: Some(PathBuf::from("/a")),
+                ..Filter::default()
+            };
+            let loaded = load_history(&[], Some(&store), &filter);
+            assert_eq!(
+                commands(&loaded.entries),
+                [("make", Some(1000))],
+                "{backend:?}"
+            );
+            assert_eq!(loaded.entries[0].run_count, 1, "{backend:?}");
+
+            let filter = Filter {
+                exit_status: Some(1),
+                ..Filter::default()
+            };
+            let loaded = load_history(&[], Some(&store), &filter);
+            assert_eq!(
+                commands(&loaded.entries),
+                [("make", Some(2000))],
+                "{backend:?}"
+            );
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_recorded_metadata_over_untimestamped_copies() {
+        let dir = temp_dir("recorded-metadata");
+        let store = Store {
+            backend: Backend::Jsonl,
+            path: dir.join("history.jsonl"),
+        };
+        store
+            .append(&[Record {
+                command: "make build".to_string(),
+                shell: Shell::Bash,
+                timestamp: 1000,
+                duration: None,
+                cwd: Some(PathBuf::from("/proj")),
+                exit_status: Some(0),
+                hostname: None,
+                session: None,
+                tags: Vec::new(),
+            }])
+            .unwrap();
+        let bash = dir.join(".bash_history");
+        let source = [HistorySource {
+            path: bash.clone(),
+            shell: Shell::Bash,
+        }];
+
+        // The file's copy of the recorded run isn't counted again
+        fs::write(&bash, "ls\nmake build\n").unwrap();
+        let loaded = load_history(&source, Some(&store), &Filter::default());
+        let build = &loaded.entries[1];
+        assert_eq!(build.command, "make build");
+        assert_eq!(build.run_count, 1);
+        assert_eq!(build.cwd.as_deref(), Some(Path::new("/proj")));
+
+        // A run only the file has is, but the store's metadata still shows
+        fs::write(&bash, "make build\nls\nmake build\n").unwrap();
+        let loaded = load_history(&source, Some(&store), &Filter::default());
+        let build = &loaded.entries[1];
+        assert_eq!(build.command, "make build");
+        assert_eq!(build.run_count, 2);
+        assert_eq!(build.cwd.as_deref(), Some(Path::new("/proj")));
+        assert_eq!(build.exit_status, Some(0));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
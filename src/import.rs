@@ -0,0 +1,341 @@
+//! Reading history kept by other tools, for `iff import`.
+
+use crate::config::Dirs;
+use crate::history::{Filter, Shell};
+use crate::store::{Record, RunIndex, Store};
+use clap::ValueEnum;
+use color_eyre::Result;
+use color_eyre::eyre::{WrapErr, bail};
+use rusqlite::{Connection, OpenFlags};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Tool {
+    /// atuin's history.db
+    Atuin,
+    /// mcfly's history.db
+    Mcfly,
+    /// hstr's favorites, ~/.hstr_favorites
+    Hstr,
+}
+
+impl Tool {
+    /// Where the tool keeps its history by default.
+    pub fn default_path(self) -> PathBuf {
+        let dirs = Dirs::from_env();
+        match self {
+            Tool::Atuin => dirs.data.join("atuin/history.db"),
+            Tool::Mcfly => {
+                // Older versions kept it in the home directory
+                let legacy = dirs.home.join(".mcfly/history.db");
+                if legacy.is_file() {
+                    legacy
+                } else {
+                    dirs.data.join("mcfly/history.db")
+                }
+            }
+            Tool::Hstr => dirs.home.join(".hstr_favorites"),
+        }
+    }
+}
+
+/// Reads every command in the `tool`'s history at `path`, oldest first.
+/// Neither atuin nor mcfly record the shell, so entries are marked as `shell`.
+pub fn read(tool: Tool, path: &Path, shell: Shell) -> Result<Vec<Record>> {
+    if !path.is_file() {
+        bail!(
+            "{} doesn't exist; pass the path to import from",
+            path.display()
+        );
+    }
+    let records = match tool {
+        Tool::Atuin => open(path).and_then(|conn| read_atuin(&conn, shell)),
+        Tool::Mcfly => open(path).and_then(|conn| read_mcfly(&conn, shell)),
+        Tool::Hstr => read_hstr(path, shell),
+    };
+    records.wrap_err_with(|| format!("failed to read {}", path.display()))
+}
+
+/// Appends the `records` that aren't in `store` yet and returns how many
+/// that was. A run is already there if the same command was recorded within
+/// a couple of seconds of it, so importing again adds nothing.
+pub fn add_new(store: &Store, records: Vec<Record>) -> Result<usize> {
+    let mut runs = if store.exists() {
+        RunIndex::new(&store.load(&Filter::default())?.0)
+    } else {
+        RunIndex::default()
+    };
+    let records: Vec<_> = records
+        .into_iter()
+        .filter(|record| {
+            let new = !runs.contains(&record.command, record.timestamp);
+            runs.insert(&record.command, record.timestamp);
+            new
+        })
+        .collect();
+    if !records.is_empty() {
+        store.append(&records)?;
+    }
+    Ok(records.len())
+}
+
+fn open(path: &Path) -> Result<Connection> {
+    // Read-only, so a database the tool has open is left alone
+    Ok(Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?)
+}
+
+/// atuin keeps times in nanoseconds, and the hostname as `host:user`.
+/// Entries deleted in atuin (newer versions only mark them) are skipped.
+fn read_atuin(conn: &Connection, shell: Shell) -> Result<Vec<Record>> {
+    let has_deleted_at: bool = conn.query_row(
+        "SELECT count(*) > 0 FROM pragma_table_info('history') WHERE name = 'deleted_at'",
+        [],
+        |row| row.get(0),
+    )?;
+    let sql = format!(
+        "SELECT timestamp, duration, exit, command, cwd, session, hostname
+         FROM history {}
+         ORDER BY timestamp",
+        if has_deleted_at {
+            "WHERE deleted_at IS NULL"
+        } else {
+            ""
+        }
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map([], |row| {
+        let timestamp: i64 = row.get(0)?;
+        let duration: i64 = row.get(1)?;
+        let hostname: Option<String> = row.get(6)?;
+        Ok(Record {
+            command: row.get(3)?,
+            shell,
+            timestamp: u64::try_from(timestamp / 1_000_000_000).unwrap_or(0),
+            // -1 while the command was still running
+            duration: u64::try_from(duration).ok().map(|ns| ns / 1_000_000_000),
+            cwd: row.get::<_, Option<String>>(4)?.map(PathBuf::from),
+            exit_status: row.get(2)?,
+            hostname: hostname.map(|host| match host.split_once(':') {
+                Some((host, _user)) => host.to_string(),
+                None => host,
+            }),
+            session: row.get(5)?,
+            tags: Vec::new(),
+        })
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+fn read_mcfly(conn: &Connection, shell: Shell) -> Result<Vec<Record>> {
+    let mut stmt = conn.prepare(
+        "SELECT cmd, session_id, when_run, exit_code, dir
+         FROM commands
+         ORDER BY when_run, id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        let when_run: i64 = row.get(2)?;
+        Ok(Record {
+            command: row.get(0)?,
+            shell,
+            timestamp: u64::try_from(when_run).unwrap_or(0),
+            duration: None,
+            cwd: row.get::<_, Option<String>>(4)?.map(PathBuf::from),
+            exit_status: row.get(3)?,
+            hostname: None,
+            session: row.get(1)?,
+            tags: Vec::new(),
+        })
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// One command per line, with no times of their own; they're given the
+/// file's modification time and tagged `favorite`.
+fn read_hstr(path: &Path, shell: Shell) -> Result<Vec<Record>> {
+    let content = fs::read_to_string(path)?;
+    let timestamp = fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    Ok(content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Record {
+            command: line.to_string(),
+            shell,
+            timestamp,
+            duration: None,
+            cwd: None,
+            exit_status: None,
+            hostname: None,
+            session: None,
+            tags: vec!["favorite".to_string()],
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Backend;
+    use std::env;
+
+    /// A fresh, empty directory for one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("iff-test-{}-import-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn commands(records: &[Record]) -> Vec<&str> {
+        records
+            .iter()
+            .map(|record| record.command.as_str())
+            .collect()
+    }
+
+    /// An atuin database, with or without the newer `deleted_at` column.
+    fn atuin_fixture(path: &Path, deleted_at: bool) {
+        let conn = Connection::open(path).unwrap();
+        conn.execute_batch(&format!(
+            "CREATE TABLE history (
+                id TEXT PRIMARY KEY,
+                timestamp INTEGER NOT NULL,
+                duration INTEGER NOT NULL,
+                exit INTEGER NOT NULL,
+                command TEXT NOT NULL,
+                cwd TEXT NOT NULL,
+                session TEXT NOT NULL,
+                hostname TEXT NOT NULL
+                {}
+            );
+            INSERT INTO history VALUES
+                ('b', 1700000060000000000, -1, -1, 'sleep 100', '/tmp', 's2', 'box:me' {}),
+                ('a', 1700000000500000000, 2500000000, 0, 'make', '/proj', 's1', 'box:me' {});",
+            if deleted_at {
+                ", deleted_at INTEGER"
+            } else {
+                ""
+            },
+            if deleted_at { ", NULL" } else { "" },
+            if deleted_at { ", NULL" } else { "" },
+        ))
+        .unwrap();
+        if deleted_at {
+            conn.execute(
+                "INSERT INTO history VALUES
+                    ('c', 1700000090000000000, 0, 0, 'rm -rf oops', '/', 's2', 'other', 1)",
+                [],
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn reads_atuin() {
+        let dir = temp_dir("atuin");
+        for deleted_at in [false, true] {
+            let path = dir.join(format!("history-{deleted_at}.db"));
+            atuin_fixture(&path, deleted_at);
+            let records = read(Tool::Atuin, &path, Shell::Zsh).unwrap();
+            // Oldest first, and without what atuin marked deleted
+            assert_eq!(commands(&records), ["make", "sleep 100"], "{deleted_at}");
+
+            let make = &records[0];
+            assert_eq!(make.shell, Shell::Zsh);
+            assert_eq!(make.timestamp, 1700000000);
+            assert_eq!(make.duration, Some(2));
+            assert_eq!(make.exit_status, Some(0));
+            assert_eq!(make.cwd.as_deref(), Some(Path::new("/proj")));
+            assert_eq!(make.session.as_deref(), Some("s1"));
+            assert_eq!(make.hostname.as_deref(), Some("box"));
+
+            // Still running when atuin last saw it
+            let sleep = &records[1];
+            assert_eq!(sleep.duration, None);
+            assert_eq!(sleep.exit_status, Some(-1));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_mcfly() {
+        let dir = temp_dir("mcfly");
+        let path = dir.join("history.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE commands (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                cmd TEXT NOT NULL,
+                cmd_tpl TEXT,
+                session_id TEXT NOT NULL,
+                when_run INTEGER NOT NULL,
+                exit_code INTEGER NOT NULL,
+                selected INTEGER NOT NULL,
+                dir TEXT,
+                old_dir TEXT
+            );
+            INSERT INTO commands (cmd, session_id, when_run, exit_code, selected, dir) VALUES
+                ('cargo test', 's1', 1700000100, 101, 0, '/proj'),
+                ('git status', 's1', 1700000000, 0, 0, '/proj'),
+                ('ls', 's2', 1700000100, 0, 0, NULL);",
+        )
+        .unwrap();
+        drop(conn);
+
+        let records = read(Tool::Mcfly, &path, Shell::Bash).unwrap();
+        assert_eq!(commands(&records), ["git status", "cargo test", "ls"]);
+        let test = &records[1];
+        assert_eq!(test.timestamp, 1700000100);
+        assert_eq!(test.exit_status, Some(101));
+        assert_eq!(test.cwd.as_deref(), Some(Path::new("/proj")));
+        assert_eq!(test.session.as_deref(), Some("s1"));
+        assert_eq!(test.duration, None);
+        assert_eq!(records[2].cwd, None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_hstr_favorites() {
+        let dir = temp_dir("hstr");
+        let path = dir.join(".hstr_favorites");
+        fs::write(&path, "git log --oneline\n\n  \nmake\n").unwrap();
+        let records = read(Tool::Hstr, &path, Shell::Bash).unwrap();
+        assert_eq!(commands(&records), ["git log --oneline", "make"]);
+        assert_eq!(records[0].tags, ["favorite"]);
+        assert!(read(Tool::Hstr, &dir.join("missing"), Shell::Bash).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn adds_only_new_runs() {
+        let dir = temp_dir("add-new");
+        let path = dir.join("history.db");
+        atuin_fixture(&path, true);
+        let records = read(Tool::Atuin, &path, Shell::Zsh).unwrap();
+        let store = Store {
+            backend: Backend::Jsonl,
+            path: dir.join("history.jsonl"),
+        };
+        // A run recorded a second apart is the same one
+        store
+            .append(&[Record {
+                timestamp: 1700000001,
+                ..records[0].clone()
+            }])
+            .unwrap();
+
+        assert_eq!(add_new(&store, records.clone()).unwrap(), 1);
+        assert_eq!(add_new(&store, records).unwrap(), 0);
+        let (stored, _) = store.load(&Filter::default()).unwrap();
+        let stored: Vec<_> = stored.iter().map(|entry| entry.command.as_str()).collect();
+        assert_eq!(stored, ["make", "sleep 100"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,13 @@
+use crate::history::Shell;
+
+/// The script `iff init <shell>` prints. It binds Ctrl-R to a widget that
+/// runs `iff --print` with the current line as the query and puts the chosen
+/// command back on the line for editing, and hooks every command into
+/// `iff record`.
+pub fn script(shell: Shell) -> &'static str {
+    match shell {
+        Shell::Bash => include_str!("shell/iff.bash"),
+        Shell::Zsh => include_str!("shell/iff.zsh"),
+        Shell::Fish => include_str!("shell/iff.fish"),
+    }
+}
@@ -0,0 +1,297 @@
+use crate::history::HistoryEntry;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ratatui::Frame;
+use ratatui::layout::{Position, Rect};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph};
+
+/// A single-line text input with a cursor, readline style.
+///
+/// Newlines in the text (multi-line commands) are kept as-is and shown as ↵.
+#[derive(Default)]
+pub struct LineEditor {
+    chars: Vec<char>,
+    /// Char index the cursor sits before; `chars.len()` is the end
+    cursor: usize,
+    completion: Option<Completion>,
+}
+
+/// An in-progress Tab completion, cycled through by pressing Tab again.
+struct Completion {
+    /// Where the word being completed starts
+    start: usize,
+    candidates: Vec<String>,
+    index: usize,
+}
+
+impl LineEditor {
+    pub fn new(text: &str) -> Self {
+        let chars: Vec<char> = text.chars().collect();
+        Self {
+            cursor: chars.len(),
+            chars,
+            completion: None,
+        }
+    }
+
+    pub fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    /// Applies an editing key; any other key is ignored. `history` is what
+    /// Tab completes from.
+    pub fn handle_key(&mut self, key: KeyEvent, history: &[HistoryEntry]) {
+        if key.code != KeyCode::Tab {
+            self.completion = None;
+        }
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+        match key.code {
+            KeyCode::Char('a') if ctrl => self.cursor = 0,
+            KeyCode::Char('e') if ctrl => self.cursor = self.chars.len(),
+            KeyCode::Char('w') if ctrl => self.delete_word_before(),
+            KeyCode::Char('u') if ctrl => {
+                self.chars.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            KeyCode::Char('k') if ctrl => self.chars.truncate(self.cursor),
+            KeyCode::Char('b') if alt => self.cursor = self.word_start(),
+            KeyCode::Char('f') if alt => self.cursor = self.word_end(),
+            KeyCode::Char(_) if ctrl || alt => {}
+            KeyCode::Char(c) => self.insert(c),
+            KeyCode::Backspace => self.backspace(),
+            KeyCode::Delete => self.delete(),
+            KeyCode::Left if ctrl || alt => self.cursor = self.word_start(),
+            KeyCode::Right if ctrl || alt => self.cursor = self.word_end(),
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.chars.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.chars.len(),
+            KeyCode::Tab => self.complete(history),
+            _ => {}
+        }
+    }
+
+    fn insert(&mut self, c: char) {
+        self.chars.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.chars.len() {
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    /// Start of the word before the cursor, skipping whitespace first like
+    /// readline's Ctrl-W.
+    fn word_start(&self) -> usize {
+        let mut i = self.cursor;
+        while i > 0 && self.chars[i - 1].is_whitespace() {
+            i -= 1;
+        }
+        while i > 0 && !self.chars[i - 1].is_whitespace() {
+            i -= 1;
+        }
+        i
+    }
+
+    /// End of the word after the cursor, like readline's Alt-F.
+    fn word_end(&self) -> usize {
+        let mut i = self.cursor;
+        while i < self.chars.len() && self.chars[i].is_whitespace() {
+            i += 1;
+        }
+        while i < self.chars.len() && !self.chars[i].is_whitespace() {
+            i += 1;
+        }
+        i
+    }
+
+    fn delete_word_before(&mut self) {
+        let start = self.word_start();
+        self.chars.drain(start..self.cursor);
+        self.cursor = start;
+    }
+
+    /// Completes the word before the cursor from words used in history,
+    /// most recent first. Repeated presses cycle through the candidates.
+    fn complete(&mut self, history: &[HistoryEntry]) {
+        if let Some(completion) = &mut self.completion {
+            completion.index = (completion.index + 1) % completion.candidates.len();
+        } else {
+            let start = self.cursor
+                - self.chars[..self.cursor]
+                    .iter()
+                    .rev()
+                    .take_while(|c| !c.is_whitespace())
+                    .count();
+            let prefix: String = self.chars[start..self.cursor].iter().collect();
+            if prefix.is_empty() {
+                return;
+            }
+            let mut candidates = vec![prefix.clone()];
+            for word in history.iter().flat_map(|e| e.command.split_whitespace()) {
+                if word.len() > prefix.len()
+                    && word.starts_with(&prefix)
+                    && !candidates.iter().any(|c| c == word)
+                {
+                    candidates.push(word.to_string());
+                }
+            }
+            if candidates.len() == 1 {
+                return;
+            }
+            // Index 0 is what was typed, so cycling can get back to it
+            self.completion = Some(Completion {
+                start,
+                candidates,
+                index: 1,
+            });
+        }
+
+        let Some(completion) = &self.completion else {
+            return;
+        };
+        let word: Vec<char> = completion.candidates[completion.index].chars().collect();
+        self.chars
+            .splice(completion.start..self.cursor, word.iter().copied());
+        self.cursor = completion.start + word.len();
+    }
+
+    /// Draws the text inside `block`, scrolled so the cursor stays visible,
+    /// and places the terminal cursor.
+    pub fn render(&self, frame: &mut Frame, area: Rect, block: Block) {
+        let inner = block.inner(area);
+        let shown: String = self
+            .chars
+            .iter()
+            .map(|&c| if c == '\n' { '↵' } else { c })
+            .collect();
+        let before: String = shown.chars().take(self.cursor).collect();
+        let cursor_x = Line::from(before).width() as u16;
+        let scroll = cursor_x.saturating_sub(inner.width.saturating_sub(1));
+
+        let paragraph = Paragraph::new(shown).block(block).scroll((0, scroll));
+        frame.render_widget(paragraph, area);
+        frame.set_cursor_position(Position::new(inner.x + cursor_x - scroll, inner.y));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Shell;
+
+    /// An editor holding `text`, with the cursor where the `|` is.
+    fn editor(text: &str) -> LineEditor {
+        let cursor = text.chars().position(|c| c == '|').unwrap();
+        LineEditor {
+            cursor,
+            ..LineEditor::new(&text.replace('|', ""))
+        }
+    }
+
+    /// The text with `|` where the cursor is.
+    fn shown(editor: &LineEditor) -> String {
+        let mut text = editor.chars.clone();
+        text.insert(editor.cursor, '|');
+        text.into_iter().collect()
+    }
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn ctrl(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::CONTROL)
+    }
+
+    fn alt(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::ALT)
+    }
+
+    #[test]
+    fn edits_text() {
+        let cases = [
+            ("ab|", key(KeyCode::Left), "a|b"),
+            ("|ab", key(KeyCode::Left), "|ab"),
+            ("a|b", key(KeyCode::Right), "ab|"),
+            ("ab|", key(KeyCode::Right), "ab|"),
+            ("a|b", key(KeyCode::Home), "|ab"),
+            ("a|b", key(KeyCode::End), "ab|"),
+            ("a|b", ctrl(KeyCode::Char('a')), "|ab"),
+            ("a|b", ctrl(KeyCode::Char('e')), "ab|"),
+            // Word motion skips the whitespace next to the cursor first
+            ("git commit -m|", ctrl(KeyCode::Left), "git commit |-m"),
+            ("git  |commit", alt(KeyCode::Char('b')), "|git  commit"),
+            ("git com|mit", alt(KeyCode::Left), "git |commit"),
+            ("|git commit", ctrl(KeyCode::Right), "git| commit"),
+            ("git| commit -m", alt(KeyCode::Char('f')), "git commit| -m"),
+            ("git commit|", alt(KeyCode::Right), "git commit|"),
+            ("git commit  |", ctrl(KeyCode::Char('w')), "git |"),
+            ("git com|mit", ctrl(KeyCode::Char('w')), "git |mit"),
+            ("|git", ctrl(KeyCode::Char('w')), "|git"),
+            ("git com|mit", ctrl(KeyCode::Char('u')), "|mit"),
+            ("git com|mit", ctrl(KeyCode::Char('k')), "git com|"),
+            ("a|bc", key(KeyCode::Delete), "a|c"),
+            ("ab|", key(KeyCode::Delete), "ab|"),
+            ("hé|", key(KeyCode::Backspace), "h|"),
+            ("|ab", key(KeyCode::Backspace), "|ab"),
+            ("a|c", key(KeyCode::Char('b')), "ab|c"),
+            (
+                "a|",
+                KeyEvent::new(KeyCode::Char('B'), KeyModifiers::SHIFT),
+                "aB|",
+            ),
+            // Unhandled keys leave it alone
+            ("a|b", alt(KeyCode::Char('x')), "a|b"),
+            ("a|b", ctrl(KeyCode::Char('r')), "a|b"),
+            ("a|b", key(KeyCode::Up), "a|b"),
+        ];
+        for (before, key, after) in cases {
+            let mut editor = editor(before);
+            editor.handle_key(key, &[]);
+            assert_eq!(shown(&editor), after, "{before:?} with {key:?}");
+        }
+    }
+
+    #[test]
+    fn completes_words_from_history() {
+        let history: Vec<HistoryEntry> = [
+            "git checkout main",
+            "git cherry-pick abc",
+            "git checkout dev",
+        ]
+        .into_iter()
+        .map(|command| HistoryEntry::new(command.to_string(), Shell::Bash))
+        .collect();
+
+        let mut editor = editor("git ch| x");
+        let tab = |editor: &mut LineEditor| {
+            editor.handle_key(key(KeyCode::Tab), &history);
+            shown(editor)
+        };
+        assert_eq!(tab(&mut editor), "git checkout| x");
+        assert_eq!(tab(&mut editor), "git cherry-pick| x");
+        // Cycles back round through what was typed
+        assert_eq!(tab(&mut editor), "git ch| x");
+        assert_eq!(tab(&mut editor), "git checkout| x");
+
+        // Any other key ends the completion
+        editor.handle_key(key(KeyCode::Backspace), &history);
+        assert_eq!(tab(&mut editor), "git checkout| x");
+
+        for text in ["git |", "git xyz|", "git checkout|"] {
+            let mut editor = LineEditor::new(&text.replace('|', ""));
+            assert_eq!(tab(&mut editor), text);
+        }
+    }
+}
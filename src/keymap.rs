@@ -0,0 +1,330 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Everything a key can be bound to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    SelectNext,
+    SelectPrevious,
+    SelectFirst,
+    SelectLast,
+    PageDown,
+    PageUp,
+    /// Run the selected command
+    Accept,
+    /// Open the selected command in the editor first
+    AcceptAndEdit,
+    /// Hide the selected entry for the rest of the session
+    DeleteEntry,
+    /// Show or hide details of the selected entry
+    TogglePreview,
+    InsertMode,
+    NormalMode,
+    Quit,
+    /// Unbinds a default binding
+    Ignore,
+}
+
+/// A single key press, e.g. `ctrl-n`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl From<KeyEvent> for Key {
+    fn from(event: KeyEvent) -> Self {
+        Key::new(event.code, event.modifiers)
+    }
+}
+
+#[derive(Debug)]
+pub struct KeyParseError(String);
+
+impl fmt::Display for KeyParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid key '{}'", self.0)
+    }
+}
+
+impl std::error::Error for KeyParseError {}
+
+impl Key {
+    /// Keeps only the modifiers that tell keys apart, the same way for key
+    /// events as for parsed bindings.
+    fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        let mut modifiers =
+            modifiers & (KeyModifiers::CONTROL | KeyModifiers::ALT | KeyModifiers::SHIFT);
+        // Shift is already part of the char itself ('G' vs 'g'), and of
+        // BackTab, which terminals report with it
+        if matches!(code, KeyCode::Char(_) | KeyCode::BackTab) {
+            modifiers.remove(KeyModifiers::SHIFT);
+        }
+        Key { code, modifiers }
+    }
+
+    /// Parses key notation like `j`, `G`, `enter`, `ctrl-n`, `alt-f` or
+    /// `shift-tab`.
+    pub fn parse(s: &str) -> Result<Self, KeyParseError> {
+        let err = || KeyParseError(s.to_string());
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = s;
+        // A lone "-" is a key, not an empty modifier
+        while let Some((prefix, tail)) = rest.split_once('-')
+            && !tail.is_empty()
+        {
+            modifiers |= match prefix.to_lowercase().as_str() {
+                "ctrl" | "c" => KeyModifiers::CONTROL,
+                "alt" | "m" | "meta" => KeyModifiers::ALT,
+                "shift" | "s" => KeyModifiers::SHIFT,
+                _ => return Err(err()),
+            };
+            rest = tail;
+        }
+
+        let mut chars = rest.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(c),
+            _ => match rest.to_lowercase().as_str() {
+                "enter" | "return" => KeyCode::Enter,
+                "esc" | "escape" => KeyCode::Esc,
+                "tab" if modifiers.contains(KeyModifiers::SHIFT) => KeyCode::BackTab,
+                "tab" => KeyCode::Tab,
+                "backtab" => KeyCode::BackTab,
+                "space" => KeyCode::Char(' '),
+                "backspace" => KeyCode::Backspace,
+                "delete" | "del" => KeyCode::Delete,
+                "insert" => KeyCode::Insert,
+                "up" => KeyCode::Up,
+                "down" => KeyCode::Down,
+                "left" => KeyCode::Left,
+                "right" => KeyCode::Right,
+                "home" => KeyCode::Home,
+                "end" => KeyCode::End,
+                "pageup" => KeyCode::PageUp,
+                "pagedown" => KeyCode::PageDown,
+                f => match f.strip_prefix('f').and_then(|n| n.parse().ok()) {
+                    Some(n @ 1..=12) => KeyCode::F(n),
+                    _ => return Err(err()),
+                },
+            },
+        };
+
+        let code = match code {
+            // ctrl-N and ctrl-n are the same key to a terminal
+            KeyCode::Char(c) if modifiers.contains(KeyModifiers::CONTROL) => {
+                KeyCode::Char(c.to_ascii_lowercase())
+            }
+            KeyCode::Char(c) if modifiers.contains(KeyModifiers::SHIFT) => {
+                KeyCode::Char(c.to_ascii_uppercase())
+            }
+            code => code,
+        };
+        Ok(Key::new(code, modifiers))
+    }
+}
+
+/// A space-separated run of keys, e.g. `g g`.
+pub fn parse_sequence(s: &str) -> Result<Vec<Key>, KeyParseError> {
+    let keys: Vec<Key> = s
+        .split_whitespace()
+        .map(Key::parse)
+        .collect::<Result<_, _>>()?;
+    if keys.is_empty() {
+        return Err(KeyParseError(s.to_string()));
+    }
+    Ok(keys)
+}
+
+/// Bindings for one mode.
+#[derive(Clone, Debug, Default)]
+pub struct Bindings(HashMap<Vec<Key>, Action>);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Lookup {
+    Action(Action),
+    /// The keys so far start a longer binding; wait for more
+    Pending,
+    NoMatch,
+}
+
+impl Bindings {
+    fn from_defaults(defaults: &[(&str, Action)]) -> Self {
+        let map = defaults
+            .iter()
+            .map(|(keys, action)| {
+                let keys = parse_sequence(keys).expect("default keymap is valid");
+                (keys, *action)
+            })
+            .collect();
+        Bindings(map)
+    }
+
+    pub fn bind(&mut self, keys: Vec<Key>, action: Action) {
+        self.0.insert(keys, action);
+    }
+
+    pub fn lookup(&self, keys: &[Key]) -> Lookup {
+        match self.0.get(keys) {
+            Some(Action::Ignore) => Lookup::NoMatch,
+            Some(&action) => Lookup::Action(action),
+            None if self
+                .0
+                .keys()
+                .any(|bound| bound.len() > keys.len() && bound.starts_with(keys)) =>
+            {
+                Lookup::Pending
+            }
+            None => Lookup::NoMatch,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Keymap {
+    pub insert: Bindings,
+    pub normal: Bindings,
+}
+
+const DEFAULT_INSERT: &[(&str, Action)] = &[
+    ("down", Action::SelectNext),
+    ("up", Action::SelectPrevious),
+    ("ctrl-n", Action::SelectNext),
+    ("ctrl-p", Action::SelectPrevious),
+    ("pagedown", Action::PageDown),
+    ("pageup", Action::PageUp),
+    ("enter", Action::Accept),
+    ("tab", Action::AcceptAndEdit),
+    ("ctrl-o", Action::TogglePreview),
+    ("esc", Action::NormalMode),
+    ("ctrl-c", Action::Quit),
+];
+
+const DEFAULT_NORMAL: &[(&str, Action)] = &[
+    ("down", Action::SelectNext),
+    ("up", Action::SelectPrevious),
+    ("j", Action::SelectNext),
+    ("k", Action::SelectPrevious),
+    ("ctrl-n", Action::SelectNext),
+    ("ctrl-p", Action::SelectPrevious),
+    ("g g", Action::SelectFirst),
+    ("G", Action::SelectLast),
+    ("pagedown", Action::PageDown),
+    ("pageup", Action::PageUp),
+    ("ctrl-d", Action::PageDown),
+    ("ctrl-u", Action::PageUp),
+    ("enter", Action::Accept),
+    ("tab", Action::AcceptAndEdit),
+    ("d d", Action::DeleteEntry),
+    ("p", Action::TogglePreview),
+    ("ctrl-o", Action::TogglePreview),
+    ("i", Action::InsertMode),
+    ("a", Action::InsertMode),
+    ("/", Action::InsertMode),
+    ("q", Action::Quit),
+    ("esc", Action::Quit),
+    ("ctrl-c", Action::Quit),
+];
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap {
+            insert: Bindings::from_defaults(DEFAULT_INSERT),
+            normal: Bindings::from_defaults(DEFAULT_NORMAL),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> Key {
+        Key { code, modifiers }
+    }
+
+    fn event(code: KeyCode, modifiers: KeyModifiers) -> Key {
+        Key::from(KeyEvent::new(code, modifiers))
+    }
+
+    #[test]
+    fn parses_key_notation() {
+        let none = KeyModifiers::NONE;
+        let ctrl = KeyModifiers::CONTROL;
+        let alt = KeyModifiers::ALT;
+        let cases = [
+            ("j", key(KeyCode::Char('j'), none)),
+            ("G", key(KeyCode::Char('G'), none)),
+            ("shift-g", key(KeyCode::Char('G'), none)),
+            ("-", key(KeyCode::Char('-'), none)),
+            ("ctrl--", key(KeyCode::Char('-'), ctrl)),
+            ("ctrl-n", key(KeyCode::Char('n'), ctrl)),
+            ("ctrl-N", key(KeyCode::Char('n'), ctrl)),
+            ("C-n", key(KeyCode::Char('n'), ctrl)),
+            ("alt-f", key(KeyCode::Char('f'), alt)),
+            ("ctrl-alt-x", key(KeyCode::Char('x'), ctrl | alt)),
+            ("enter", key(KeyCode::Enter, none)),
+            ("Esc", key(KeyCode::Esc, none)),
+            ("space", key(KeyCode::Char(' '), none)),
+            ("tab", key(KeyCode::Tab, none)),
+            ("shift-tab", key(KeyCode::BackTab, none)),
+            ("backtab", key(KeyCode::BackTab, none)),
+            ("shift-up", key(KeyCode::Up, KeyModifiers::SHIFT)),
+            ("f5", key(KeyCode::F(5), none)),
+            ("pagedown", key(KeyCode::PageDown, none)),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(Key::parse(input).unwrap(), expected, "input: {input:?}");
+        }
+
+        for input in ["", "hyper-x", "ctrl-", "f13", "f0", "enterr"] {
+            assert!(Key::parse(input).is_err(), "input: {input:?}");
+        }
+    }
+
+    #[test]
+    fn parsed_keys_match_key_events() {
+        let shift = KeyModifiers::SHIFT;
+        let cases = [
+            // crossterm reports ESC [ Z as BackTab with shift held
+            ("shift-tab", event(KeyCode::BackTab, shift)),
+            ("backtab", event(KeyCode::BackTab, shift)),
+            ("G", event(KeyCode::Char('G'), shift)),
+            ("ctrl-n", event(KeyCode::Char('n'), KeyModifiers::CONTROL)),
+            ("shift-up", event(KeyCode::Up, shift)),
+            ("j", event(KeyCode::Char('j'), KeyModifiers::NONE)),
+        ];
+        for (input, event) in cases {
+            assert_eq!(Key::parse(input).unwrap(), event, "input: {input:?}");
+        }
+    }
+
+    #[test]
+    fn looks_up_bindings() {
+        let mut bindings = Bindings::from_defaults(DEFAULT_NORMAL);
+        bindings.bind(parse_sequence("q").unwrap(), Action::Ignore);
+        bindings.bind(parse_sequence("space f").unwrap(), Action::PageDown);
+
+        let lookup = |keys: &str| bindings.lookup(&parse_sequence(keys).unwrap());
+        let cases = [
+            ("j", Lookup::Action(Action::SelectNext)),
+            ("G", Lookup::Action(Action::SelectLast)),
+            ("g", Lookup::Pending),
+            ("g g", Lookup::Action(Action::SelectFirst)),
+            ("g x", Lookup::NoMatch),
+            ("d", Lookup::Pending),
+            ("space", Lookup::Pending),
+            ("space f", Lookup::Action(Action::PageDown)),
+            ("x", Lookup::NoMatch),
+            // Unbound by the user
+            ("q", Lookup::NoMatch),
+            ("esc", Lookup::Action(Action::Quit)),
+        ];
+        for (keys, expected) in cases {
+            assert_eq!(lookup(keys), expected, "keys: {keys:?}");
+        }
+    }
+}
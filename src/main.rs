@@ -1,43 +1,246 @@
-use clap::Parser;
+mod config;
+mod db;
+mod exec;
+mod export;
+mod history;
+mod import;
+mod init;
+mod input;
+mod keymap;
+mod matcher;
+mod store;
+mod theme;
+mod tui;
+
+use clap::{Args, Parser, Subcommand};
 use color_eyre::Result;
-use crossterm::ExecutableCommand;
-use crossterm::cursor::Show;
-use crossterm::event::{self, Event, KeyCode, KeyEvent};
+use color_eyre::eyre::bail;
+use config::Config;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+use exec::Executor;
+use history::{HistoryEntry, LoadedHistory, Shell, TimeRange};
+use input::LineEditor;
+use keymap::{Action, Key, Keymap, Lookup};
+use matcher::{Match, MatchMode, SortOrder};
 use ratatui::Frame;
 use ratatui::layout::{Constraint, Layout, Rect};
-use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::style::{Style, Stylize};
 use ratatui::text::{Line, Span};
-use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
-use std::env;
-use std::fs;
-use std::io::stdout;
-use std::os::unix::process::CommandExt;
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use std::fs::OpenOptions;
+use std::io::{Write, stdout};
+use std::mem;
 use std::path::PathBuf;
-use std::process::Command;
+use store::Store;
+use theme::Theme;
+use tui::Screen;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    #[command(flatten)]
+    query: Query,
+
+    /// Print the selected command instead of running it
+    #[arg(long)]
+    print: bool,
+
+    /// With --print, write to this file descriptor instead of stdout
+    #[arg(long, value_name = "FD", requires = "print")]
+    print_fd: Option<u32>,
+
+    /// Shell used to run the selected command [default: $SHELL]
+    #[arg(long, value_name = "PATH")]
+    exec_shell: Option<PathBuf>,
+
+    /// Run the shell interactively (-i) so aliases and functions are loaded
+    #[arg(long, conflicts_with = "direct")]
+    interactive: bool,
+
+    /// Run the command without a shell, splitting it into words ourselves
+    #[arg(long, conflicts_with = "exec_shell")]
+    direct: bool,
+
+    /// Draw inline below the prompt, this many rows tall, instead of
+    /// fullscreen
+    #[arg(long, value_name = "ROWS", value_parser = clap::value_parser!(u16).range(i64::from(config::MIN_HEIGHT)..))]
+    height: Option<u16>,
+
+    /// Use the whole screen even if the config sets a height
+    #[arg(long, conflicts_with = "height")]
+    fullscreen: bool,
+
+    /// Config file to use [default: $XDG_CONFIG_HOME/iff/config.toml]
+    #[arg(long, value_name = "PATH", global = true)]
+    config: Option<PathBuf>,
+}
+
+/// What to search for and in which history; shared by the TUI and the
+/// commands that run without it.
+#[derive(Args)]
+struct Query {
     /// Command to un-forget
     args: Vec<String>,
+
+    /// History file to read instead of the default locations (repeatable)
+    #[arg(long = "history-file", value_name = "PATH")]
+    history_files: Vec<PathBuf>,
+
+    /// Only read history for this shell
+    #[arg(long, value_enum)]
+    shell: Option<Shell>,
+
+    /// Only show commands run after this time (e.g. 2d, 3h, 2024-05-01)
+    #[arg(long, value_parser = history::parse_time)]
+    since: Option<u64>,
+
+    /// Only show commands run before this time
+    #[arg(long, value_parser = history::parse_time)]
+    until: Option<u64>,
+
+    /// Only show commands recorded as run in this directory
+    #[arg(long, value_name = "DIR")]
+    cwd: Option<PathBuf>,
+
+    /// Only show commands recorded as exiting with this status
+    #[arg(long, value_name = "STATUS", allow_negative_numbers = true)]
+    exit_status: Option<i32>,
+
+    /// Use fuzzy matching, even if the config says otherwise
+    #[arg(long, conflicts_with_all = ["exact", "regex"])]
+    fuzzy: bool,
+
+    /// Match the search as a plain substring instead of fuzzy matching
+    #[arg(long, conflicts_with = "regex")]
+    exact: bool,
+
+    /// Treat the search as a case-insensitive regular expression
+    #[arg(long)]
+    regex: bool,
+
+    /// Order of the results [default: score]
+    #[arg(long, value_enum)]
+    sort: Option<SortOrder>,
 }
 
-static PATHS: &[&str] = &[".bash_history", ".zsh_history"];
+#[derive(Subcommand)]
+enum Commands {
+    /// Print shell key bindings for Ctrl-R, e.g. `eval "$(iff init zsh)"`
+    Init {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Save a finished command to iff's own history. The `iff init` scripts
+    /// call this after every command
+    Record {
+        #[arg(long, value_enum)]
+        shell: Shell,
+        /// Exit status of the command
+        #[arg(long = "exit", value_name = "STATUS", allow_negative_numbers = true)]
+        exit_status: Option<i32>,
+        /// How long it ran for
+        #[arg(long, value_name = "SECONDS")]
+        duration: Option<u64>,
+        /// Directory it was started in
+        #[arg(long, value_name = "PATH")]
+        cwd: Option<PathBuf>,
+        /// Any string identifying the shell session
+        #[arg(long, value_name = "ID")]
+        session: Option<String>,
+        /// Label for the entry (repeatable)
+        #[arg(long = "tag", value_name = "TAG", value_parser = db::parse_tag)]
+        tags: Vec<String>,
+        /// The command line as typed
+        command: String,
+    },
+    /// Add the history kept by atuin, mcfly or hstr to iff's own. Commands
+    /// already there are skipped, so it's safe to run again
+    Import {
+        #[arg(value_enum)]
+        tool: import::Tool,
+        /// Database or file to read [default: where the tool keeps it]
+        path: Option<PathBuf>,
+        /// Shell the commands were run in [default: from $SHELL]
+        #[arg(long, value_enum)]
+        shell: Option<Shell>,
+    },
+    /// Write the merged history to stdout, oldest first. Takes the same
+    /// search and filters as the TUI and writes what it would list
+    Export {
+        #[arg(long, value_enum)]
+        format: export::Format,
+        #[command(flatten)]
+        query: Query,
+    },
+    /// Print the commands matching a search, best first, without the TUI.
+    /// Exits with status 1 if nothing matches
+    Search {
+        /// Print at most this many matches
+        #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        limit: Option<usize>,
+        /// Print a JSON array with each match's score, index and details
+        /// instead of one command per line
+        #[arg(long)]
+        json: bool,
+        #[command(flatten)]
+        query: Query,
+    },
+    /// Show where the config file is read from
+    Config {
+        /// Print a commented config with every default value instead
+        #[arg(long)]
+        print_default: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Keys type into the search box
+    Insert,
+    /// Keys navigate the list, vim style
+    Normal,
+}
 
 struct App {
     should_quit: bool,
-    command_history: Vec<String>,
+    command_history: Vec<HistoryEntry>,
     list_state: ListState,
-    search_input: String,
-    filtered_commands: Vec<usize>,
+    search: LineEditor,
+    filtered_commands: Vec<Match>,
+    match_mode: MatchMode,
+    sort: SortOrder,
+    theme: Theme,
     selected_command: Option<String>,
+    warnings: Vec<String>,
+    /// Set while the selected command is being edited before it runs
+    editing: Option<LineEditor>,
+    mode: Mode,
+    keymap: Keymap,
+    /// Keys typed so far of a possible multi-key binding
+    pending_keys: Vec<KeyEvent>,
+    /// Rows visible in the list, for paging
+    page_size: usize,
+    /// Drawn inline with a few rows rather than fullscreen
+    compact: bool,
+    show_preview: bool,
 }
 
 impl App {
-    fn new(initial_args: Vec<String>) -> Result<Self> {
-        let command_history = Self::load_history()?;
-        let search_input = initial_args.join(" ");
-        let filtered_commands = Self::filter_commands(&command_history, &search_input);
+    fn new(
+        initial_args: Vec<String>,
+        loaded: LoadedHistory,
+        match_mode: MatchMode,
+        sort: SortOrder,
+        keymap: Keymap,
+        theme: Theme,
+    ) -> Result<Self> {
+        let command_history = loaded.entries;
+        let search = LineEditor::new(&initial_args.join(" "));
+        let filtered_commands =
+            Self::filter_commands(&command_history, &search.text(), match_mode, sort);
 
         let mut list_state = ListState::default();
         if !filtered_commands.is_empty() {
@@ -48,59 +251,39 @@ impl App {
             should_quit: false,
             command_history,
             list_state,
-            search_input,
+            search,
             filtered_commands,
+            match_mode,
+            sort,
+            theme,
             selected_command: None,
+            warnings: loaded.warnings,
+            editing: None,
+            mode: Mode::Insert,
+            keymap,
+            pending_keys: Vec::new(),
+            page_size: 10,
+            compact: false,
+            show_preview: false,
         })
     }
 
-    fn load_history() -> Result<Vec<String>> {
-        let data_home = env::var_os("HOME").expect("HOME isn't set");
-        let base_path = PathBuf::from(data_home);
-
-        for path in PATHS {
-            let full_path = base_path.join(path);
-            if full_path.exists() {
-                let content = fs::read_to_string(full_path)?;
-                let mut commands: Vec<String> = content
-                    .lines()
-                    .map(|line| {
-                        // zsh starts with :
-                        if line.starts_with(":") {
-                            if let Some(pos) = line.find(';') {
-                                return line[pos + 1..].to_string();
-                            }
-                        }
-                        line.to_string()
-                    })
-                    .filter(|cmd| !cmd.trim().is_empty())
-                    .collect();
-
-                commands.reverse();
-                let mut seen = std::collections::HashSet::new();
-                commands.retain(|cmd| seen.insert(cmd.clone()));
-
-                return Ok(commands);
-            }
-        }
-
-        Ok(Vec::new())
-    }
-    fn filter_commands(commands: &[String], query: &str) -> Vec<usize> {
-        if query.is_empty() {
-            return (0..commands.len()).collect();
-        }
-
-        commands
-            .iter()
-            .enumerate()
-            .filter(|(_, cmd)| cmd.to_lowercase().contains(&query.to_lowercase()))
-            .map(|(i, _)| i)
-            .collect()
+    fn filter_commands(
+        commands: &[HistoryEntry],
+        query: &str,
+        mode: MatchMode,
+        sort: SortOrder,
+    ) -> Vec<Match> {
+        matcher::filter(commands, query, mode, sort)
     }
 
     fn update_filter(&mut self) {
-        self.filtered_commands = Self::filter_commands(&self.command_history, &self.search_input);
+        self.filtered_commands = Self::filter_commands(
+            &self.command_history,
+            &self.search.text(),
+            self.match_mode,
+            self.sort,
+        );
 
         // Reset selection to first item
         if !self.filtered_commands.is_empty() {
@@ -111,36 +294,128 @@ impl App {
     }
 
     fn handle_key(&mut self, key: KeyEvent) {
-        match key.code {
-            KeyCode::Char('q') | KeyCode::Esc => {
-                self.should_quit = true;
+        if self.editing.is_some() {
+            self.handle_edit_key(key);
+            return;
+        }
+
+        self.pending_keys.push(key);
+        let keys: Vec<Key> = self.pending_keys.iter().map(|&k| Key::from(k)).collect();
+        let bindings = match self.mode {
+            Mode::Insert => &self.keymap.insert,
+            Mode::Normal => &self.keymap.normal,
+        };
+        match bindings.lookup(&keys) {
+            Lookup::Action(action) => {
+                self.pending_keys.clear();
+                self.perform(action);
             }
-            KeyCode::Down | KeyCode::Char('j') => {
-                self.select_next();
+            Lookup::Pending => {}
+            Lookup::NoMatch => {
+                // The first key wasn't the start of a binding after all;
+                // treat it as unbound and replay the rest
+                let pending = mem::take(&mut self.pending_keys);
+                self.handle_unbound_key(pending[0]);
+                for &key in &pending[1..] {
+                    self.handle_key(key);
+                }
             }
-            KeyCode::Up | KeyCode::Char('k') => {
-                self.select_previous();
+        }
+    }
+
+    /// In insert mode unbound keys type into the search box; in normal mode
+    /// they do nothing.
+    fn handle_unbound_key(&mut self, key: KeyEvent) {
+        if self.mode == Mode::Normal {
+            return;
+        }
+        let before = self.search.text();
+        self.search.handle_key(key, &self.command_history);
+        if self.search.text() != before {
+            self.update_filter();
+        }
+    }
+
+    fn perform(&mut self, action: Action) {
+        match action {
+            Action::SelectNext => self.select_next(),
+            Action::SelectPrevious => self.select_previous(),
+            Action::SelectFirst => self.select_index(0),
+            Action::SelectLast => self.select_index(usize::MAX),
+            Action::PageDown => {
+                let i = self.list_state.selected().unwrap_or(0);
+                self.select_index(i.saturating_add(self.page_size));
             }
-            KeyCode::Char(c) => {
-                self.search_input.push(c);
-                self.update_filter();
+            Action::PageUp => {
+                let i = self.list_state.selected().unwrap_or(0);
+                self.select_index(i.saturating_sub(self.page_size));
             }
-            KeyCode::Backspace => {
-                self.search_input.pop();
-                self.update_filter();
+            Action::Accept => {
+                self.selected_command = self.current_command().map(str::to_string);
+                self.should_quit = true;
             }
-            KeyCode::Enter => {
-                if let Some(selected) = self.list_state.selected() {
-                    if let Some(&cmd_idx) = self.filtered_commands.get(selected) {
-                        self.selected_command = Some(self.command_history[cmd_idx].clone());
-                    }
+            Action::AcceptAndEdit => {
+                if let Some(command) = self.current_command() {
+                    self.editing = Some(LineEditor::new(command));
                 }
+            }
+            Action::DeleteEntry => self.delete_selected(),
+            Action::TogglePreview => self.show_preview = !self.show_preview,
+            Action::InsertMode => self.mode = Mode::Insert,
+            Action::NormalMode => self.mode = Mode::Normal,
+            Action::Quit => self.should_quit = true,
+            Action::Ignore => {}
+        }
+    }
+
+    /// Selects `i`, clamped to the list.
+    fn select_index(&mut self, i: usize) {
+        let last = self.filtered_commands.len().checked_sub(1);
+        self.list_state.select(last.map(|last| i.min(last)));
+    }
+
+    /// Drops the selected entry from this session's list. History files are
+    /// left untouched.
+    fn delete_selected(&mut self) {
+        let Some(selected) = self.list_state.selected() else {
+            return;
+        };
+        let Some(m) = self.filtered_commands.get(selected) else {
+            return;
+        };
+        self.command_history.remove(m.index);
+        self.update_filter();
+        self.select_index(selected);
+    }
+
+    fn handle_edit_key(&mut self, key: KeyEvent) {
+        let Some(editor) = &mut self.editing else {
+            return;
+        };
+        match key.code {
+            KeyCode::Enter => {
+                self.selected_command = Some(editor.text());
+                self.should_quit = true;
+            }
+            KeyCode::Esc => self.editing = None,
+            // Bypasses the keymap, so Ctrl-C quits from here too
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
                 self.should_quit = true;
             }
-            _ => {}
+            _ => editor.handle_key(key, &self.command_history),
         }
     }
 
+    fn current_entry(&self) -> Option<&HistoryEntry> {
+        let selected = self.list_state.selected()?;
+        let m = self.filtered_commands.get(selected)?;
+        Some(&self.command_history[m.index])
+    }
+
+    fn current_command(&self) -> Option<&str> {
+        self.current_entry().map(|entry| entry.command.as_str())
+    }
+
     fn select_next(&mut self) {
         if self.filtered_commands.is_empty() {
             return;
@@ -178,26 +453,72 @@ impl App {
     }
 
     fn render(&mut self, frame: &mut Frame) {
-        let vertical = Layout::vertical([
-            Constraint::Length(1),
-            Constraint::Length(3),
-            Constraint::Fill(1),
-            Constraint::Length(1),
-        ])
-        .spacing(1);
-        let [top, search, main, bottom] = vertical.areas(frame.area());
-
-        let title = Line::from_iter([
-            Span::from("I f****** forgot").bold(),
-            Span::from(" (Press 'q' to quit, ↑↓ to navigate, Enter to select)"),
-        ]);
-        frame.render_widget(title.centered(), top);
-
-        let search_block = Block::bordered().title("Search").style(Style::new().cyan());
-        let search_text = Paragraph::new(self.search_input.as_str()).block(search_block);
-        frame.render_widget(search_text, search);
-
-        self.render_command_list(frame, main);
+        let (mode, help) = if self.editing.is_some() {
+            ("EDIT", " (Enter to run, Esc to go back, Tab to complete)")
+        } else {
+            match self.mode {
+                Mode::Insert => (
+                    "INSERT",
+                    " (Type to search, ↑↓ to navigate, Enter to select, Tab to edit, Esc for normal mode)",
+                ),
+                Mode::Normal => (
+                    "NORMAL",
+                    " (Press 'q' to quit, j/k to navigate, i to search, Enter to select, Tab to edit)",
+                ),
+            }
+        };
+        let mode = Span::from(format!(" {mode} ")).reversed();
+
+        // Inline, every row counts: no title, no gaps, and the mode moves to
+        // the status bar
+        let (search, main, bottom) = if self.compact {
+            let [search, main, bottom] = Layout::vertical([
+                Constraint::Length(3),
+                Constraint::Fill(1),
+                Constraint::Length(1),
+            ])
+            .areas(frame.area());
+            (search, main, bottom)
+        } else {
+            let [top, search, main, bottom] = Layout::vertical([
+                Constraint::Length(1),
+                Constraint::Length(3),
+                Constraint::Fill(1),
+                Constraint::Length(1),
+            ])
+            .spacing(1)
+            .areas(frame.area());
+            let title = Line::from_iter([
+                mode.clone(),
+                Span::from(" "),
+                Span::from("I f****** forgot").bold(),
+                Span::from(help),
+            ]);
+            frame.render_widget(title.centered(), top);
+            (search, main, bottom)
+        };
+
+        if let Some(editor) = &self.editing {
+            let edit_block = Block::bordered()
+                .title("Edit command")
+                .style(Style::new().fg(self.theme.edit));
+            editor.render(frame, search, edit_block);
+        } else {
+            let search_block = Block::bordered()
+                .title("Search")
+                .style(Style::new().fg(self.theme.search));
+            self.search.render(frame, search, search_block);
+        }
+
+        if self.show_preview {
+            let [list, preview] =
+                Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                    .areas(main);
+            self.render_command_list(frame, list);
+            self.render_preview(frame, preview);
+        } else {
+            self.render_command_list(frame, main);
+        }
 
         // Status bar
         let status = format!(
@@ -205,16 +526,115 @@ impl App {
             self.filtered_commands.len(),
             self.command_history.len()
         );
-        frame.render_widget(Line::from(status).centered().dim(), bottom);
+        let mut status_line = Line::default();
+        if self.compact {
+            status_line.push_span(mode);
+            status_line.push_span(" ");
+        }
+        status_line.push_span(Span::from(status).dim());
+        if let Some(err) = matcher::query_error(&self.search.text(), self.match_mode) {
+            status_line.push_span(Span::from(format!("  {err}")).red());
+        }
+        if let Some(warning) = self.warnings.first() {
+            let more = match self.warnings.len() {
+                1 => String::new(),
+                n => format!(" (+{} more)", n - 1),
+            };
+            status_line.push_span(Span::from(format!("  ⚠ {warning}{more}")).yellow());
+        }
+        frame.render_widget(status_line.centered(), bottom);
+    }
+
+    /// Everything known about the selected entry, with the command wrapped
+    /// in full.
+    fn render_preview(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered()
+            .title("Preview")
+            .style(Style::new().fg(self.theme.list));
+        let Some(entry) = self.current_entry() else {
+            frame.render_widget(block, area);
+            return;
+        };
+
+        let mut lines: Vec<Line> = entry
+            .command
+            .lines()
+            .map(|line| Line::from(line).bold())
+            .collect();
+        lines.push(Line::default());
+        let mut field = |name: &str, value: String| {
+            lines.push(Line::from_iter([
+                Span::from(format!("{name:<10}")).dim(),
+                Span::from(value),
+            ]));
+        };
+        match &entry.source {
+            Some(source) => field(
+                "Source",
+                format!("{} ({})", source.display(), entry.shell.name()),
+            ),
+            None => field("Shell", entry.shell.name().to_string()),
+        }
+        if let Some(ts) = entry.timestamp {
+            let age = history::now().saturating_sub(ts);
+            field(
+                "When",
+                format!(
+                    "{} ({} ago)",
+                    history::format_timestamp(ts),
+                    history::format_seconds(age)
+                ),
+            );
+        }
+        if let Some(duration) = entry.duration {
+            field("Took", history::format_seconds(duration));
+        }
+        if let Some(cwd) = &entry.cwd {
+            field("Directory", cwd.display().to_string());
+        }
+        if let Some(status) = entry.exit_status {
+            field("Exit", status.to_string());
+        }
+        if let Some(hostname) = &entry.hostname {
+            field("Host", hostname.clone());
+        }
+        if let Some(session) = &entry.session {
+            field("Session", session.clone());
+        }
+        field("Runs", entry.run_count.to_string());
+        if !entry.tags.is_empty() {
+            field("Tags", entry.tags.join(", "));
+        }
+        if !entry.paths.is_empty() {
+            field("Paths", entry.paths.join(" "));
+        }
+
+        let preview = Paragraph::new(lines)
+            .block(block)
+            .wrap(Wrap { trim: false });
+        frame.render_widget(preview, area);
     }
 
     fn render_command_list(&mut self, frame: &mut Frame, area: Rect) {
+        // Minus the borders
+        self.page_size = usize::from(area.height.saturating_sub(2)).max(1);
         let items: Vec<ListItem> = self
             .filtered_commands
             .iter()
-            .map(|&idx| {
-                let cmd = &self.command_history[idx];
-                ListItem::new(cmd.as_str())
+            .map(|m| {
+                let entry = &self.command_history[m.index];
+                let mut meta = format!("  {}", entry.shell.name());
+                if let Some(ts) = entry.timestamp {
+                    let age = history::now().saturating_sub(ts);
+                    meta.push_str(&format!(" · {} ago", history::format_seconds(age)));
+                }
+                if let Some(duration) = entry.duration.filter(|&d| d > 0) {
+                    meta.push_str(&format!(" · took {}", history::format_seconds(duration)));
+                }
+                let mut spans =
+                    highlight_command(&entry.command, &m.positions, self.theme.highlight_style());
+                spans.push(Span::from(meta).dim());
+                ListItem::new(Line::from(spans))
             })
             .collect();
 
@@ -222,57 +642,220 @@ impl App {
             .block(
                 Block::bordered()
                     .title("Command History")
-                    .style(Style::new().blue()),
-            )
-            .highlight_style(
-                Style::new()
-                    .bg(Color::DarkGray)
-                    .add_modifier(Modifier::BOLD),
+                    .style(Style::new().fg(self.theme.list)),
             )
+            .highlight_style(self.theme.selection_style())
             .highlight_symbol(">> ");
 
         frame.render_stateful_widget(list, area, &mut self.list_state);
     }
 }
 
-fn parse_command_string(input: &str) -> (String, Vec<String>) {
-    let mut parts = Vec::new();
-    let mut current = String::new();
-    let mut in_quotes = false;
-
-    for c in input.chars() {
-        match c {
-            '"' => {
-                in_quotes = !in_quotes;
-            }
-            ' ' if !in_quotes => {
-                if !current.is_empty() {
-                    parts.push(current.clone());
-                    current.clear();
-                }
-            }
-            _ => {
-                current.push(c);
+/// Splits a command into spans with the matched char positions highlighted.
+/// Multi-line commands are shown on one row, lines joined by ↵.
+fn highlight_command<'a>(command: &'a str, positions: &[usize], matched: Style) -> Vec<Span<'a>> {
+    let mut spans = Vec::new();
+    let mut positions = positions.iter().peekable();
+    let mut run_start = 0;
+    let mut run_matched = false;
+
+    for (char_idx, (byte_idx, c)) in command.char_indices().enumerate() {
+        let is_match = positions.next_if_eq(&&char_idx).is_some();
+        if c == '\n' || is_match != run_matched {
+            let run = &command[run_start..byte_idx];
+            if !run.is_empty() {
+                spans.push(if run_matched {
+                    Span::styled(run, matched)
+                } else {
+                    Span::from(run)
+                });
             }
+            run_start = byte_idx;
+            run_matched = is_match;
+        }
+        if c == '\n' {
+            spans.push(Span::from(" ↵ ").dim());
+            run_start = byte_idx + 1;
         }
     }
-
-    if !current.is_empty() {
-        parts.push(current);
+    let run = &command[run_start..];
+    if !run.is_empty() {
+        spans.push(if run_matched {
+            Span::styled(run, matched)
+        } else {
+            Span::from(run)
+        });
     }
+    spans
+}
 
-    let program = parts.first().unwrap_or(&String::new()).clone();
-    let args = parts.into_iter().skip(1).collect();
-
-    (program, args)
+/// Loads the history and matches it against `query`, with the config
+/// filling in whatever the command line leaves out.
+fn load_app(query: Query, config: &Config) -> Result<App> {
+    // Files named on the command line are all that's read; otherwise iff's
+    // own recorded history joins the shell's
+    let (history_files, store) = if query.history_files.is_empty() {
+        (
+            config.history_files(),
+            Some(Store::new(config.history.backend)),
+        )
+    } else {
+        (query.history_files, None)
+    };
+    let shell = query.shell.or(config.history.shell);
+    let sources = history::discover_sources(&history_files, shell)?;
+    let mut filter = config.filter()?;
+    filter.shell = shell;
+    filter.cwd = query.cwd.map(std::path::absolute).transpose()?;
+    filter.exit_status = query.exit_status;
+    filter.time_range = TimeRange {
+        since: query.since.or(filter.time_range.since),
+        until: query.until.or(filter.time_range.until),
+    };
+    // The config's own pair is checked when it's read
+    if let (Some(since), Some(until)) = (filter.time_range.since, filter.time_range.until)
+        && since > until
+    {
+        bail!("--since is later than --until, so nothing would be shown");
+    }
+    let loaded = history::load_history(&sources, store.as_ref(), &filter);
+    let match_mode = if query.fuzzy {
+        MatchMode::Fuzzy
+    } else if query.exact {
+        MatchMode::Exact
+    } else if query.regex {
+        MatchMode::Regex
+    } else {
+        config.search.mode
+    };
+    let sort = query.sort.unwrap_or(config.search.sort);
+    App::new(
+        query.args,
+        loaded,
+        match_mode,
+        sort,
+        config.keymap()?,
+        config.theme()?,
+    )
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
     color_eyre::install()?;
 
-    let mut app = App::new(cli.args)?;
-    let mut terminal = ratatui::init();
+    match cli.command {
+        Some(Commands::Init { shell }) => {
+            print!("{}", init::script(shell));
+            return Ok(());
+        }
+        Some(Commands::Record {
+            shell,
+            exit_status,
+            duration,
+            cwd,
+            session,
+            tags,
+            command,
+        }) => {
+            let record = store::Record {
+                command,
+                shell,
+                timestamp: history::now().saturating_sub(duration.unwrap_or(0)),
+                duration,
+                cwd,
+                exit_status,
+                hostname: store::hostname(),
+                session,
+                tags,
+            };
+            let config = Config::load(cli.config.as_deref())?;
+            Store::new(config.history.backend).record(record)?;
+            return Ok(());
+        }
+        Some(Commands::Import { tool, path, shell }) => {
+            let path = path.unwrap_or_else(|| tool.default_path());
+            let shell = shell.or_else(Shell::from_env).unwrap_or(Shell::Bash);
+            let records = import::read(tool, &path, shell)?;
+            let total = records.len();
+
+            let config = Config::load(cli.config.as_deref())?;
+            let store = Store::new(config.history.backend);
+            let added = import::add_new(&store, records)?;
+            println!(
+                "Imported {added} commands from {} ({} already in {})",
+                path.display(),
+                total - added,
+                store.path.display()
+            );
+            return Ok(());
+        }
+        Some(Commands::Export { format, query }) => {
+            let config = Config::load(cli.config.as_deref())?;
+            let app = load_app(query, &config)?;
+            for warning in &app.warnings {
+                eprintln!("iff: {warning}");
+            }
+            let mut indices: Vec<usize> = app.filtered_commands.iter().map(|m| m.index).collect();
+            // The history is newest first
+            indices.sort_unstable_by(|a, b| b.cmp(a));
+            let entries: Vec<&HistoryEntry> =
+                indices.iter().map(|&i| &app.command_history[i]).collect();
+            export::write(&mut stdout().lock(), &entries, format)?;
+            return Ok(());
+        }
+        Some(Commands::Search { limit, json, query }) => {
+            let config = Config::load(cli.config.as_deref())?;
+            let app = load_app(query, &config)?;
+            for warning in &app.warnings {
+                eprintln!("iff: {warning}");
+            }
+            let matches = &app.filtered_commands
+                [..limit.unwrap_or(usize::MAX).min(app.filtered_commands.len())];
+            let mut out = stdout().lock();
+            if json {
+                let found: Vec<_> = matches
+                    .iter()
+                    .map(|m| export::Found {
+                        index: m.index,
+                        score: m.score,
+                        positions: &m.positions,
+                        entry: (&app.command_history[m.index]).into(),
+                    })
+                    .collect();
+                serde_json::to_writer(&mut out, &found)?;
+                writeln!(out)?;
+            } else {
+                for m in matches {
+                    writeln!(out, "{}", app.command_history[m.index].command)?;
+                }
+            }
+            if app.filtered_commands.is_empty() {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Commands::Config { print_default }) => {
+            if print_default {
+                print!("{}", config::DEFAULT_CONFIG);
+            } else {
+                let path = cli.config.unwrap_or_else(Config::default_path);
+                println!("{}", path.display());
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+
+    // Command-line flags win over the config file
+    let config = Config::load(cli.config.as_deref())?;
+    let mut app = load_app(cli.query, &config)?;
+    let screen = match cli.height.or(config.ui.height) {
+        Some(height) if !cli.fullscreen => Screen::Inline(height),
+        _ => Screen::Fullscreen,
+    };
+    app.compact = screen != Screen::Fullscreen;
+    app.show_preview = config.ui.preview;
+    let mut terminal = tui::init(screen)?;
 
     loop {
         terminal.draw(|frame| app.render(frame))?;
@@ -286,12 +869,41 @@ fn main() -> Result<()> {
         }
     }
 
-    ratatui::restore();
-    let _ = stdout().execute(Show);
+    tui::restore(&mut terminal, screen)?;
+
+    if cli.print {
+        let Some(command) = app.selected_command else {
+            std::process::exit(1);
+        };
+        let mut out: Box<dyn Write> = match cli.print_fd {
+            Some(fd) => Box::new(
+                OpenOptions::new()
+                    .write(true)
+                    .open(format!("/dev/fd/{fd}"))?,
+            ),
+            None => Box::new(stdout()),
+        };
+        writeln!(out, "{command}")?;
+        return Ok(());
+    }
 
     if let Some(command) = app.selected_command {
-        let (com, args) = parse_command_string(&command);
-        let err = Command::new(com).args(args).exec();
+        // Asking for a shell on the command line overrides `direct` in the
+        // config
+        let direct =
+            cli.direct || (config.exec.direct && cli.exec_shell.is_none() && !cli.interactive);
+        let executor = if direct {
+            Executor::Direct
+        } else {
+            Executor::Shell {
+                path: cli
+                    .exec_shell
+                    .or(config.exec.shell)
+                    .unwrap_or_else(exec::default_shell),
+                interactive: cli.interactive || config.exec.interactive,
+            }
+        };
+        let err = exec::exec(&command, &executor);
         eprintln!("Failed to exec: {}", err);
         std::process::exit(1);
     }
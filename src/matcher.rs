@@ -0,0 +1,407 @@
+use crate::history::HistoryEntry;
+use clap::ValueEnum;
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    /// fzf-style subsequence matching, ranked by score
+    #[default]
+    Fuzzy,
+    /// Case-insensitive substring matching, in history order
+    Exact,
+    /// Case-insensitive regular expression, in history order
+    Regex,
+}
+
+/// How matches are ordered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    /// Best fuzzy score first, newest first among equals
+    #[default]
+    Score,
+    /// Newest first, regardless of score
+    Recency,
+}
+
+#[derive(Clone, Debug)]
+pub struct Match {
+    /// Index into the history the match was made against
+    pub index: usize,
+    pub score: i64,
+    /// Char (not byte) offsets into the command that the query matched,
+    /// ascending
+    pub positions: Vec<usize>,
+}
+
+const SCORE_MATCH: i64 = 16;
+const BONUS_BOUNDARY: i64 = 8;
+const BONUS_CAMEL: i64 = 7;
+const BONUS_CONSECUTIVE: i64 = 4;
+const BONUS_FIRST_CHAR_MULTIPLIER: i64 = 2;
+const PENALTY_GAP_START: i64 = 3;
+const PENALTY_GAP_EXTENSION: i64 = 1;
+
+const NO_MATCH: i64 = i64::MIN / 2;
+
+/// Matches `query` against every entry. An empty query matches everything.
+///
+/// With [`SortOrder::Score`], fuzzy results are sorted by descending score;
+/// ties (and the other modes) keep history order, which is newest first.
+pub fn filter(
+    entries: &[HistoryEntry],
+    query: &str,
+    mode: MatchMode,
+    sort: SortOrder,
+) -> Vec<Match> {
+    if query.is_empty() {
+        return (0..entries.len())
+            .map(|index| Match {
+                index,
+                score: 0,
+                positions: Vec::new(),
+            })
+            .collect();
+    }
+
+    let mut matches: Vec<Match> = match mode {
+        MatchMode::Exact => {
+            let query: Vec<char> = query.chars().map(lower).collect();
+            entries
+                .iter()
+                .enumerate()
+                .filter_map(|(index, entry)| {
+                    let positions = exact_match(&entry.command, &query)?;
+                    Some(Match {
+                        index,
+                        score: 0,
+                        positions,
+                    })
+                })
+                .collect()
+        }
+        MatchMode::Fuzzy => {
+            let terms: Vec<&str> = query.split_whitespace().collect();
+            entries
+                .iter()
+                .enumerate()
+                .filter_map(|(index, entry)| {
+                    let mut score = 0;
+                    let mut positions = Vec::new();
+                    for term in &terms {
+                        let (term_score, term_positions) = fuzzy_match(&entry.command, term)?;
+                        score += term_score;
+                        positions.extend(term_positions);
+                    }
+                    positions.sort_unstable();
+                    positions.dedup();
+                    Some(Match {
+                        index,
+                        score,
+                        positions,
+                    })
+                })
+                .collect()
+        }
+        MatchMode::Regex => {
+            let Ok(re) = build_regex(query) else {
+                return Vec::new();
+            };
+            entries
+                .iter()
+                .enumerate()
+                .filter_map(|(index, entry)| {
+                    let positions = regex_match(&re, &entry.command)?;
+                    Some(Match {
+                        index,
+                        score: 0,
+                        positions,
+                    })
+                })
+                .collect()
+        }
+    };
+
+    if sort == SortOrder::Score {
+        // Stable, so equal scores stay in recency order
+        matches.sort_by_key(|m| std::cmp::Reverse(m.score));
+    }
+    matches
+}
+
+/// Why `query` can't be used in `mode`, if it can't. Only regexes can be
+/// malformed.
+pub fn query_error(query: &str, mode: MatchMode) -> Option<String> {
+    if mode != MatchMode::Regex || query.is_empty() {
+        return None;
+    }
+    build_regex(query).err().map(|err| {
+        // The full error is a multi-line diagram; the last line is the reason
+        let msg = err.to_string();
+        let reason = msg.lines().last().unwrap_or_default().trim();
+        format!("invalid regex: {}", reason.trim_start_matches("error: "))
+    })
+}
+
+fn build_regex(query: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(query).case_insensitive(true).build()
+}
+
+fn lower(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+fn exact_match(haystack: &str, needle: &[char]) -> Option<Vec<usize>> {
+    let hay: Vec<char> = haystack.chars().map(lower).collect();
+    let start = hay.windows(needle.len()).position(|w| w == needle)?;
+    Some((start..start + needle.len()).collect())
+}
+
+fn regex_match(re: &Regex, haystack: &str) -> Option<Vec<usize>> {
+    let mut ranges = re.find_iter(haystack).map(|m| m.range()).peekable();
+    ranges.peek()?;
+    let mut positions = Vec::new();
+    for (char_idx, (byte_idx, _)) in haystack.char_indices().enumerate() {
+        while ranges.next_if(|r| r.end <= byte_idx).is_some() {}
+        if ranges.peek().is_some_and(|r| r.contains(&byte_idx)) {
+            positions.push(char_idx);
+        }
+    }
+    Some(positions)
+}
+
+fn bonus(prev: Option<char>, c: char) -> i64 {
+    match prev {
+        None => BONUS_BOUNDARY,
+        Some(p) if !p.is_alphanumeric() && c.is_alphanumeric() => BONUS_BOUNDARY,
+        Some(p) if p.is_lowercase() && c.is_uppercase() => BONUS_CAMEL,
+        Some(p) if !p.is_ascii_digit() && c.is_ascii_digit() => BONUS_CAMEL,
+        _ => 0,
+    }
+}
+
+/// Scores `needle` as a case-insensitive subsequence of `haystack`, returning
+/// the score and the char positions matched, or `None` if it isn't one.
+///
+/// Finds the best alignment with a Smith-Waterman style pass: every matched
+/// character scores, with bonuses for landing on word boundaries and for
+/// following the previous match directly, and an affine penalty for gaps.
+pub fn fuzzy_match(haystack: &str, needle: &str) -> Option<(i64, Vec<usize>)> {
+    let needle: Vec<char> = needle.chars().map(lower).collect();
+    if needle.is_empty() {
+        return Some((0, Vec::new()));
+    }
+    let hay: Vec<char> = haystack.chars().collect();
+    let hay_lower: Vec<char> = hay.iter().copied().map(lower).collect();
+
+    // Cheap rejection before the quadratic part
+    let mut rest = hay_lower.iter();
+    if !needle.iter().all(|c| rest.any(|h| h == c)) {
+        return None;
+    }
+
+    let bonuses: Vec<i64> = (0..hay.len())
+        .map(|j| bonus(j.checked_sub(1).map(|k| hay[k]), hay[j]))
+        .collect();
+
+    // prev[j]: best score with the needle so far matched and its last char
+    // at j. prev_chunk[j]: the bonus a consecutive run ending at j carries
+    // forward, so "dkr" in "some dkr" keeps the boundary bonus of its 'd'.
+    let mut prev: Vec<i64> = hay_lower
+        .iter()
+        .zip(&bonuses)
+        .map(|(&h, &b)| {
+            if h == needle[0] {
+                SCORE_MATCH + b * BONUS_FIRST_CHAR_MULTIPLIER
+            } else {
+                NO_MATCH
+            }
+        })
+        .collect();
+    let mut prev_chunk = bonuses.clone();
+    // back[i][j]: where the previous needle char sits in the best alignment
+    // putting needle[i + 1] at j
+    let mut back: Vec<Vec<usize>> = Vec::with_capacity(needle.len());
+
+    for &nc in &needle[1..] {
+        let mut row = vec![NO_MATCH; hay.len()];
+        let mut chunk = vec![0; hay.len()];
+        let mut from = vec![0; hay.len()];
+        // Best previous-row score that would reach j across a gap of >= 1
+        let mut carry = NO_MATCH;
+        let mut carry_from = 0;
+        for j in 1..hay.len() {
+            if j >= 2 {
+                let extended = carry - PENALTY_GAP_EXTENSION;
+                let started = prev[j - 2] - PENALTY_GAP_START;
+                if started >= extended {
+                    carry = started;
+                    carry_from = j - 2;
+                } else {
+                    carry = extended;
+                }
+            }
+            if hay_lower[j] != nc {
+                continue;
+            }
+            let run_bonus = prev_chunk[j - 1].max(bonuses[j]).max(BONUS_CONSECUTIVE);
+            let consecutive = prev[j - 1] + run_bonus;
+            let gapped = carry + bonuses[j];
+            if consecutive.max(gapped) <= NO_MATCH / 2 {
+                continue;
+            }
+            if consecutive >= gapped {
+                row[j] = consecutive + SCORE_MATCH;
+                chunk[j] = run_bonus;
+                from[j] = j - 1;
+            } else {
+                row[j] = gapped + SCORE_MATCH;
+                chunk[j] = bonuses[j];
+                from[j] = carry_from;
+            }
+        }
+        prev = row;
+        prev_chunk = chunk;
+        back.push(from);
+    }
+
+    let (end, score) = prev
+        .into_iter()
+        .enumerate()
+        .max_by_key(|&(_, score)| score)
+        .filter(|&(_, score)| score > NO_MATCH / 2)?;
+
+    let mut positions = vec![end];
+    for from in back.iter().rev() {
+        let last = positions[positions.len() - 1];
+        positions.push(from[last]);
+    }
+    positions.reverse();
+    Some((score, positions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Shell;
+
+    fn history(commands: &[&str]) -> Vec<HistoryEntry> {
+        commands
+            .iter()
+            .map(|command| HistoryEntry::new(command.to_string(), Shell::Bash))
+            .collect()
+    }
+
+    /// The matched commands with their positions, in result order.
+    fn search<'a>(
+        entries: &'a [HistoryEntry],
+        query: &str,
+        mode: MatchMode,
+        sort: SortOrder,
+    ) -> Vec<(&'a str, Vec<usize>)> {
+        filter(entries, query, mode, sort)
+            .into_iter()
+            .map(|m| (entries[m.index].command.as_str(), m.positions))
+            .collect()
+    }
+
+    fn score(haystack: &str, needle: &str) -> i64 {
+        fuzzy_match(haystack, needle).unwrap().0
+    }
+
+    #[test]
+    fn fuzzy_matches_subsequences() {
+        let cases: &[(&str, &str, Option<&[usize]>)] = &[
+            ("docker ps", "dkr", Some(&[0, 3, 5])),
+            ("docker ps", "DKR", Some(&[0, 3, 5])),
+            ("docker ps", "", Some(&[])),
+            ("docker ps", "dkrx", None),
+            ("docker ps", "rkd", None),
+            // Word starts win over earlier letters mid-word
+            ("git commit", "gc", Some(&[0, 4])),
+            ("tree_sitter_cli", "tsc", Some(&[0, 5, 12])),
+            ("getCommitMessage", "gcm", Some(&[0, 3, 9])),
+            // A consecutive run on a boundary beats a scattered match
+            ("axbxc abc", "abc", Some(&[6, 7, 8])),
+            ("héllo wörld", "hw", Some(&[0, 6])),
+        ];
+        for (haystack, needle, expected) in cases {
+            let positions = fuzzy_match(haystack, needle).map(|(_, positions)| positions);
+            assert_eq!(
+                positions.as_deref(),
+                *expected,
+                "{needle:?} in {haystack:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn fuzzy_scores_boundaries_and_runs_higher() {
+        // Boundary over mid-word
+        assert!(score("git commit", "gc") > score("logic", "gc"));
+        assert!(score("make build", "b") > score("make rebuild", "b"));
+        // Consecutive over gapped
+        assert!(score("cargo", "car") > score("c_a_r", "car"));
+        assert!(score("cargo test", "test") > score("t e s t", "test"));
+        // Shorter gaps over longer ones
+        assert!(score("ab", "ab") > score("a_b", "ab"));
+        assert!(score("axb", "ab") > score("axxxxb", "ab"));
+        // camelCase humps count as boundaries
+        assert!(score("getCommit", "c") > score("getcommit", "c"));
+    }
+
+    #[test]
+    fn filters_with_every_term() {
+        let entries = history(&["docker ps", "docker images", "ps aux", "kubectl get pods"]);
+        let fuzzy = |query| search(&entries, query, MatchMode::Fuzzy, SortOrder::Recency);
+
+        assert_eq!(fuzzy("dkr ps"), [("docker ps", vec![0, 3, 5, 7, 8])]);
+        assert_eq!(fuzzy("ps dkr"), [("docker ps", vec![0, 3, 5, 7, 8])]);
+        assert_eq!(fuzzy("dkr zz"), []);
+        assert_eq!(fuzzy("").len(), entries.len());
+    }
+
+    #[test]
+    fn sorts_by_score_or_recency() {
+        // Newest first, as the history is passed in
+        let entries = history(&["git status", "make gc", "git commit", "git checkout"]);
+        let order = |sort| -> Vec<&str> {
+            search(&entries, "gc", MatchMode::Fuzzy, sort)
+                .into_iter()
+                .map(|(command, _)| command)
+                .collect()
+        };
+
+        assert_eq!(
+            order(SortOrder::Recency),
+            ["make gc", "git commit", "git checkout"]
+        );
+        // "git commit" and "git checkout" tie, and keep their order
+        assert_eq!(
+            order(SortOrder::Score),
+            ["make gc", "git commit", "git checkout"]
+        );
+        assert!(score("make gc", "gc") > score("git commit", "gc"));
+        assert_eq!(score("git commit", "gc"), score("git checkout", "gc"));
+    }
+
+    #[test]
+    fn maps_exact_and_regex_matches_to_chars() {
+        let entries = history(&["say écho hi", "¡héllo wörld!", "echo"]);
+        let exact = |query| search(&entries, query, MatchMode::Exact, SortOrder::Score);
+        let regex = |query| search(&entries, query, MatchMode::Regex, SortOrder::Score);
+
+        assert_eq!(exact("ÉCHO"), [("say écho hi", vec![4, 5, 6, 7])]);
+        assert_eq!(exact("wö"), [("¡héllo wörld!", vec![7, 8])]);
+        assert_eq!(exact("o h"), [("say écho hi", vec![7, 8, 9])]);
+
+        assert_eq!(regex("H.LLO"), [("¡héllo wörld!", vec![1, 2, 3, 4, 5])]);
+        assert_eq!(regex("l+"), [("¡héllo wörld!", vec![3, 4, 10])]);
+        assert_eq!(regex("^echo$"), [("echo", vec![0, 1, 2, 3])]);
+        assert_eq!(regex("(unclosed"), []);
+        assert!(query_error("(unclosed", MatchMode::Regex).is_some());
+        assert!(query_error("(unclosed", MatchMode::Fuzzy).is_none());
+    }
+}
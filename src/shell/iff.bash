@@ -0,0 +1,60 @@
+# iff key bindings for bash. Load with: eval "$(iff init bash)"
+
+__iff_widget() {
+  local selected
+  # HISTFILE isn't exported by default, so hand it over explicitly
+  if selected=$(HISTFILE="$HISTFILE" iff --print -- "$READLINE_LINE"); then
+    READLINE_LINE=$selected
+    READLINE_POINT=${#READLINE_LINE}
+  fi
+}
+bind -m emacs-standard -x '"\C-r": __iff_widget'
+bind -m vi-command -x '"\C-r": __iff_widget'
+bind -m vi-insert -x '"\C-r": __iff_widget'
+
+# Record every command, with its directory, exit status and duration, in
+# iff's own history. bash has no preexec hook, so the DEBUG trap notes when
+# the first command after a prompt starts and PROMPT_COMMAND records the
+# line from history once it has finished.
+printf -v __iff_session '%s-%(%s)T' "$$" -1
+
+__iff_preexec() {
+  # Also skip commands run by completion functions
+  [[ -n $__iff_at_prompt && -z $COMP_LINE ]] || return 0
+  __iff_at_prompt=
+  printf -v __iff_start '%(%s)T' -1
+  __iff_cwd=$PWD
+}
+
+__iff_precmd() {
+  local exit_status=$? line now
+  line=$(HISTTIMEFORMAT= builtin history 1)
+  # "  123  command", with a * after the number if it was edited
+  if [[ $line =~ ^[[:space:]]*([0-9]+)[*]?[[:space:]]+(.*)$ ]]; then
+    # An unchanged number means the line wasn't saved (e.g. ignorespace)
+    if [[ -n $__iff_start && ${BASH_REMATCH[1]} != "$__iff_last" ]]; then
+      printf -v now '%(%s)T' -1
+      (iff record --shell bash --exit "$exit_status" \
+        --duration $((now - __iff_start)) --cwd "$__iff_cwd" \
+        --session "$__iff_session" -- "${BASH_REMATCH[2]}" &)
+    fi
+    __iff_last=${BASH_REMATCH[1]}
+  fi
+  __iff_start=
+}
+
+if [[ -n ${bash_preexec_imported:-} || -n ${__bp_imported:-} ]]; then
+  # bash-preexec owns the DEBUG trap; join in rather than replace it
+  preexec_functions+=(__iff_preexec)
+elif [[ $(trap -p DEBUG) != *__iff_preexec* ]]; then
+  # Keep any DEBUG trap already set, running it first so it still sees $_
+  __iff_trap=$(trap -p DEBUG)
+  __iff_trap=${__iff_trap#"trap -- '"}
+  __iff_trap=${__iff_trap%"' DEBUG"}
+  __iff_trap=${__iff_trap//"'\''"/"'"}
+  trap "${__iff_trap:+$__iff_trap$'\n'}__iff_preexec" DEBUG
+  unset __iff_trap
+fi
+# Setting the flag last keeps the rest of PROMPT_COMMAND from counting as a
+# command line
+PROMPT_COMMAND="__iff_precmd${PROMPT_COMMAND:+;$PROMPT_COMMAND};__iff_at_prompt=1"
@@ -0,0 +1,28 @@
+# iff key bindings for fish. Load with: iff init fish | source
+
+function __iff_widget
+    # string collect keeps multi-line commands in one piece
+    set -l selected (iff --print -- (commandline) | string collect)
+    and commandline --replace -- $selected
+    commandline --function repaint
+end
+bind \cr __iff_widget
+if bind -M insert >/dev/null 2>&1
+    bind -M insert \cr __iff_widget
+end
+
+# Record every command, with its directory, exit status and duration, in
+# iff's own history
+set -g __iff_session $fish_pid-(date +%s)
+
+function __iff_preexec --on-event fish_preexec
+    set -g __iff_cwd $PWD
+end
+
+function __iff_postexec --on-event fish_postexec
+    set -l exit_status $status
+    command iff record --shell fish --exit $exit_status \
+        --duration (math --scale=0 $CMD_DURATION / 1000) --cwd $__iff_cwd \
+        --session $__iff_session -- $argv[1] &
+    disown
+end
@@ -0,0 +1,343 @@
+//! iff's own history, written by `iff record` from the shell hooks that
+//! `iff init` installs. Unlike shell history files it knows each command's
+//! directory, exit status, duration, host and session.
+
+use crate::config::Dirs;
+use crate::db::Database;
+use crate::history::{Filter, HistoryEntry, Shell};
+use color_eyre::Result;
+use color_eyre::eyre::WrapErr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// One line of the store.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub command: String,
+    pub shell: Shell,
+    /// Unix time the command started
+    pub timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_status: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+impl From<Record> for HistoryEntry {
+    fn from(record: Record) -> Self {
+        HistoryEntry {
+            timestamp: Some(record.timestamp),
+            duration: record.duration,
+            cwd: record.cwd,
+            exit_status: record.exit_status,
+            hostname: record.hostname,
+            session: record.session,
+            tags: record.tags,
+            ..HistoryEntry::new(record.command, record.shell)
+        }
+    }
+}
+
+/// How the store is kept on disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// One JSON object per line, appended to
+    #[default]
+    Jsonl,
+    /// An indexed SQLite database, quicker to query once it's large
+    Sqlite,
+}
+
+const JSONL_FILE: &str = "history.jsonl";
+
+pub struct Store {
+    pub backend: Backend,
+    pub path: PathBuf,
+}
+
+impl Store {
+    /// The store for `backend` in `$XDG_DATA_HOME/iff`, falling back to
+    /// `~/.local/share/iff`.
+    pub fn new(backend: Backend) -> Self {
+        let file = match backend {
+            Backend::Jsonl => JSONL_FILE,
+            Backend::Sqlite => "history.db",
+        };
+        Store {
+            backend,
+            path: Dirs::from_env().data.join("iff").join(file),
+        }
+    }
+
+    /// Whether there's anything to load. The SQLite store counts as there
+    /// once the JSON Lines one is, as it's copied in on first use.
+    pub fn exists(&self) -> bool {
+        self.path.is_file() || (self.backend == Backend::Sqlite && self.jsonl_path().is_file())
+    }
+
+    /// Appends a command that was just run, unless it's blank or starts
+    /// with a space, which shells take to mean "don't save this".
+    pub fn record(&self, record: Record) -> Result<()> {
+        if record.command.trim().is_empty() || record.command.starts_with(char::is_whitespace) {
+            return Ok(());
+        }
+        self.append(&[record])
+    }
+
+    pub fn append(&self, records: &[Record]) -> Result<()> {
+        match self.backend {
+            Backend::Jsonl => append_jsonl(&self.path, records),
+            Backend::Sqlite => self.open_database()?.insert(records),
+        }
+    }
+
+    /// Reads the store, oldest first, along with how many records had to
+    /// be skipped. The SQLite backend only returns entries passing `filter`;
+    /// the JSON Lines one returns everything.
+    pub fn load(&self, filter: &Filter) -> Result<(Vec<HistoryEntry>, usize)> {
+        match self.backend {
+            Backend::Jsonl => load_jsonl(&self.path),
+            Backend::Sqlite => Ok((self.open_database()?.query(filter)?, 0)),
+        }
+    }
+
+    fn jsonl_path(&self) -> PathBuf {
+        self.path.with_file_name(JSONL_FILE)
+    }
+
+    /// Opens the SQLite store. Switching to it from the JSON Lines backend
+    /// shouldn't lose what that recorded, so the first time it's opened the
+    /// JSON Lines file is copied in (and left in place).
+    fn open_database(&self) -> Result<Database> {
+        let mut db = Database::open(&self.path)?;
+        let jsonl = self.jsonl_path();
+        if jsonl.is_file() && !db.has_imported(&jsonl)? {
+            let (records, _) = read_jsonl(&jsonl)?;
+            db.import_once(&jsonl, &records)?;
+        }
+        Ok(db)
+    }
+}
+
+/// When each command was run, to spot the same run in two histories.
+#[derive(Default)]
+pub struct RunIndex(HashMap<String, Vec<u64>>);
+
+impl RunIndex {
+    pub fn new(entries: &[HistoryEntry]) -> Self {
+        let mut index = RunIndex::default();
+        for entry in entries {
+            if let Some(ts) = entry.timestamp {
+                index.insert(&entry.command, ts);
+            }
+        }
+        index
+    }
+
+    pub fn insert(&mut self, command: &str, ts: u64) {
+        self.0.entry(command.to_string()).or_default().push(ts);
+    }
+
+    /// Whether `command` ran within a couple of seconds of `ts`; shells and
+    /// tools round start times differently.
+    pub fn contains(&self, command: &str, ts: u64) -> bool {
+        self.0
+            .get(command)
+            .is_some_and(|runs| runs.iter().any(|&run| run.abs_diff(ts) <= 2))
+    }
+}
+
+/// Appends `records` in a single write, so concurrent shells can't interleave
+/// their lines.
+fn append_jsonl(path: &Path, records: &[Record]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).wrap_err_with(|| format!("failed to create {}", dir.display()))?;
+    }
+    let mut lines = String::new();
+    for record in records {
+        lines.push_str(&serde_json::to_string(record)?);
+        lines.push('\n');
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(lines.as_bytes()))
+        .wrap_err_with(|| format!("failed to write to {}", path.display()))
+}
+
+fn load_jsonl(path: &Path) -> Result<(Vec<HistoryEntry>, usize)> {
+    let (records, skipped) = read_jsonl(path)?;
+    Ok((records.into_iter().map(Into::into).collect(), skipped))
+}
+
+/// Lines that don't parse, e.g. a write cut short, are skipped and counted.
+fn read_jsonl(path: &Path) -> Result<(Vec<Record>, usize)> {
+    let content = fs::read_to_string(path)?;
+    let mut records = Vec::new();
+    let mut skipped = 0;
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        match serde_json::from_str::<Record>(line) {
+            Ok(record) => records.push(record),
+            Err(_) => skipped += 1,
+        }
+    }
+    Ok((records, skipped))
+}
+
+pub fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: the length passed is the buffer's, and gethostname
+    // NUL-terminates on success (the buffer is zeroed in case it truncates)
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len() - 1) };
+    if ret != 0 {
+        return None;
+    }
+    let name = CStr::from_bytes_until_nul(&buf).ok()?;
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// A fresh, empty directory for one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("iff-test-{}-store-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn record(command: &str) -> Record {
+        Record {
+            command: command.to_string(),
+            shell: Shell::Bash,
+            timestamp: 1000,
+            duration: None,
+            cwd: None,
+            exit_status: None,
+            hostname: None,
+            session: None,
+            tags: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn skips_unreadable_lines() {
+        let dir = temp_dir("skip");
+        let path = dir.join(JSONL_FILE);
+        fs::write(
+            &path,
+            concat!(
+                r#"{"command":"ls","shell":"bash","timestamp":1000}"#,
+                "\n\n   \n",
+                r#"{"command":"pwd","shell":"bash","timesta"#,
+                "\nnot json\n",
+                r#"{"command":"cd","shell":"tcsh","timestamp":1001}"#,
+                "\n",
+                r#"{"command":"make","shell":"zsh","timestamp":1002}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+        let (records, skipped) = read_jsonl(&path).unwrap();
+        let commands: Vec<_> = records
+            .iter()
+            .map(|record| record.command.as_str())
+            .collect();
+        assert_eq!(commands, ["ls", "make"]);
+        assert_eq!(skipped, 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn defaults_missing_fields() {
+        let record: Record =
+            serde_json::from_str(r#"{"command":"ls","shell":"fish","timestamp":1000}"#).unwrap();
+        assert_eq!(record.shell, Shell::Fish);
+        assert_eq!(record.duration, None);
+        assert_eq!(record.cwd, None);
+        assert_eq!(record.exit_status, None);
+        assert_eq!(record.hostname, None);
+        assert_eq!(record.session, None);
+        assert!(record.tags.is_empty());
+
+        // and leaves them out when writing
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"command":"ls","shell":"fish","timestamp":1000}"#
+        );
+        for field in ["command", "shell", "timestamp"] {
+            let mut value = serde_json::to_value(&record).unwrap();
+            value.as_object_mut().unwrap().remove(field);
+            assert!(
+                serde_json::from_value::<Record>(value).is_err(),
+                "field: {field:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn records_only_what_shells_would_save() {
+        let dir = temp_dir("record");
+        let store = Store {
+            backend: Backend::Jsonl,
+            path: dir.join(JSONL_FILE),
+        };
+        for command in ["ls", " secret", "", "  ", "\tsecret", "make build "] {
+            store.record(record(command)).unwrap();
+        }
+        let (entries, _) = store.load(&Filter::default()).unwrap();
+        let commands: Vec<_> = entries.iter().map(|entry| entry.command.as_str()).collect();
+        assert_eq!(commands, ["ls", "make build "]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn matches_runs_a_couple_of_seconds_apart() {
+        let mut runs = RunIndex::default();
+        runs.insert("make", 1000);
+        let cases = [
+            ("make", 997, false),
+            ("make", 998, true),
+            ("make", 1000, true),
+            ("make", 1002, true),
+            ("make", 1003, false),
+            ("make test", 1000, false),
+        ];
+        for (command, ts, expected) in cases {
+            assert_eq!(
+                runs.contains(command, ts),
+                expected,
+                "command: {command:?}, ts: {ts}"
+            );
+        }
+
+        let entries = [
+            HistoryEntry {
+                timestamp: Some(2000),
+                ..HistoryEntry::new("ls".to_string(), Shell::Bash)
+            },
+            HistoryEntry::new("pwd".to_string(), Shell::Bash),
+        ];
+        let runs = RunIndex::new(&entries);
+        assert!(runs.contains("ls", 2001));
+        // Untimestamped entries can't be matched to anything
+        assert!(!runs.contains("pwd", 0));
+    }
+}
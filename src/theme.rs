@@ -0,0 +1,35 @@
+use ratatui::style::{Color, Modifier, Style};
+
+/// Colors used by the TUI.
+#[derive(Clone, Copy, Debug)]
+pub struct Theme {
+    pub search: Color,
+    pub list: Color,
+    pub edit: Color,
+    /// The matched characters of each command
+    pub highlight: Color,
+    /// Background of the selected row
+    pub selection: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            search: Color::Cyan,
+            list: Color::Blue,
+            edit: Color::Yellow,
+            highlight: Color::Yellow,
+            selection: Color::DarkGray,
+        }
+    }
+}
+
+impl Theme {
+    pub fn highlight_style(&self) -> Style {
+        Style::new().fg(self.highlight).add_modifier(Modifier::BOLD)
+    }
+
+    pub fn selection_style(&self) -> Style {
+        Style::new().bg(self.selection).add_modifier(Modifier::BOLD)
+    }
+}
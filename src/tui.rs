@@ -0,0 +1,136 @@
+use color_eyre::Result;
+use color_eyre::eyre::bail;
+use crossterm::cursor::Show;
+use crossterm::execute;
+use crossterm::terminal::{
+    self, EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode,
+};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::Rect;
+use ratatui::{Terminal, TerminalOptions, Viewport};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::panic;
+
+pub type Tui = Terminal<CrosstermBackend<File>>;
+
+/// Where the UI is drawn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Screen {
+    /// The alternate screen, like a pager
+    Fullscreen,
+    /// This many rows below the cursor, leaving the rest of the terminal and
+    /// its scrollback alone
+    Inline(u16),
+}
+
+/// Opens the controlling terminal directly rather than using stdout, so the
+/// UI still shows up when stdout is captured, e.g. `$(iff --print)`.
+fn open_tty() -> Result<File> {
+    Ok(OpenOptions::new().read(true).write(true).open("/dev/tty")?)
+}
+
+pub fn init(screen: Screen) -> Result<Tui> {
+    let mut tty = open_tty()?;
+    enable_raw_mode()?;
+    let viewport = match screen {
+        Screen::Fullscreen => {
+            execute!(tty, EnterAlternateScreen)?;
+            Viewport::Fullscreen
+        }
+        Screen::Inline(height) => match inline_area(&mut tty, height) {
+            Ok(area) => Viewport::Fixed(area),
+            Err(err) => {
+                let _ = disable_raw_mode();
+                return Err(err);
+            }
+        },
+    };
+
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let _ = reset(screen);
+        hook(info);
+    }));
+
+    Ok(Terminal::with_options(
+        CrosstermBackend::new(tty),
+        TerminalOptions { viewport },
+    )?)
+}
+
+pub fn restore(terminal: &mut Tui, screen: Screen) -> Result<()> {
+    if let Screen::Inline(_) = screen {
+        // Wipe the UI and leave the cursor where it started, so the prompt
+        // comes back in the same place
+        let area = terminal.get_frame().area();
+        terminal.clear()?;
+        terminal.set_cursor_position(area.as_position())?;
+    }
+    reset(screen)
+}
+
+fn reset(screen: Screen) -> Result<()> {
+    let mut tty = open_tty()?;
+    disable_raw_mode()?;
+    if screen == Screen::Fullscreen {
+        execute!(tty, LeaveAlternateScreen)?;
+    }
+    execute!(tty, Show)?;
+    Ok(())
+}
+
+/// Makes room for `height` rows starting on the line after the cursor (or
+/// on the cursor's line if it's empty), scrolling the terminal if needed.
+fn inline_area(tty: &mut File, height: u16) -> Result<Rect> {
+    let (width, rows) = terminal::size()?;
+    let height = height.min(rows);
+    let (column, row) = cursor_position(tty)?;
+    let newlines = height - 1 + u16::from(column > 0);
+    // Raw mode, so \n only moves down (scrolling at the bottom)
+    tty.write_all("\n".repeat(usize::from(newlines)).as_bytes())?;
+    tty.flush()?;
+    let bottom = (row + newlines).min(rows - 1);
+    Ok(Rect::new(0, bottom + 1 - height, width, height))
+}
+
+/// Asks the terminal where the cursor is, as (column, row) from 0.
+///
+/// crossterm has this too, but it writes the query to stdout, which may be
+/// captured.
+fn cursor_position(tty: &mut File) -> Result<(u16, u16)> {
+    // Scoped, as it clashes with Write::by_ref in crossterm's macros
+    use std::io::Read;
+
+    tty.write_all(b"\x1b[6n")?;
+    tty.flush()?;
+
+    // The reply is ESC [ <row> ; <column> R
+    let mut reply = Vec::new();
+    let mut byte = [0];
+    while reply.last() != Some(&b'R') {
+        let mut fds = libc::pollfd {
+            fd: tty.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `fds` is a single valid pollfd that outlives the call
+        let ready = unsafe { libc::poll(&mut fds, 1, 1000) };
+        if ready <= 0 || reply.len() > 32 {
+            bail!("the terminal didn't report the cursor position; try without --height");
+        }
+        tty.read_exact(&mut byte)?;
+        reply.push(byte[0]);
+    }
+
+    let reply = String::from_utf8_lossy(&reply);
+    let position = reply
+        .rsplit_once('[')
+        .and_then(|(_, rest)| rest.strip_suffix('R')?.split_once(';'))
+        .and_then(|(row, column)| Some((column.parse::<u16>().ok()?, row.parse::<u16>().ok()?)));
+    match position {
+        Some((column, row)) => Ok((column.saturating_sub(1), row.saturating_sub(1))),
+        None => bail!("unexpected cursor position reply {reply:?}"),
+    }
+}